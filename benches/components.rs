@@ -0,0 +1,64 @@
+use ecs_lib_rs::World;
+use eyre::Result;
+use std::hint::black_box;
+use std::time::Instant;
+
+const ENTITIES: usize = 10_000;
+const ITERATIONS: u32 = 100;
+
+fn world() -> Result<World> {
+    let mut world = World::new();
+    world.register_component::<u32>()?;
+    world.register_component::<f32>()?;
+    for index in 0..ENTITIES {
+        world.create_entity().with_component(index as u32)?;
+        if index % 2 == 0 {
+            world.add_component_to_entity_by_id(index, index as f32)?;
+        }
+    }
+    Ok(world)
+}
+
+fn bench(name: &str, mut f: impl FnMut() -> Result<()>) -> Result<()> {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f()?;
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<24} {:>12?} per iteration", name, elapsed);
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let mut world = world()?;
+
+    bench("query run + borrow_mut", || {
+        let results = world.query().with_component::<u32>()?.run();
+        for value in &results.components[0] {
+            *value.borrow_mut().downcast_mut::<u32>().unwrap() += 1;
+        }
+        Ok(())
+    })?;
+
+    bench("each_mut", || {
+        world.each_mut(|_, value: &mut u32| *value += 1)
+    })?;
+
+    bench("query run + borrow", || {
+        let results = world.query().with_component::<u32>()?.run();
+        let sum = results.components[0]
+            .iter()
+            .map(|value| *value.borrow().downcast_ref::<u32>().unwrap() as u64)
+            .sum::<u64>();
+        black_box(sum);
+        Ok(())
+    })?;
+
+    bench("collect_copy", || {
+        let (_, values) = world.collect_copy::<u32>();
+        black_box(values.iter().map(|&value| value as u64).sum::<u64>());
+        Ok(())
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use crate::entities::builder::EntityBuilder;
+use crate::entities::mask::Mask;
+use crate::entities::EntityId;
+use crate::WorldGeneric;
+use eyre::Result;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+
+type Command<M> = Box<dyn FnOnce(&mut WorldGeneric<M>) -> Result<()>>;
+
+/// The recorded commands. Entity commands are applied before resource commands, each in the order
+/// they were queued.
+pub(crate) struct CommandQueue<M: Mask> {
+    entities: Vec<Command<M>>,
+    resources: Vec<Command<M>>,
+}
+
+impl<M: Mask> Default for CommandQueue<M> {
+    fn default() -> Self {
+        Self {
+            entities: vec![],
+            resources: vec![],
+        }
+    }
+}
+
+impl<M: Mask> CommandQueue<M> {
+    /// Applies every queued command, even after one fails, and returns the first failure.
+    pub(crate) fn apply(self, world: &mut WorldGeneric<M>) -> Result<()> {
+        let mut failure = None;
+        for command in self.entities.into_iter().chain(self.resources) {
+            if let Err(error) = command(world) {
+                failure.get_or_insert(error);
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+/// Records structural changes into the world's command queue, obtained from `World::commands`.
+/// It only needs a shared borrow of the world, so systems that can't mutate the world can still
+/// spawn, despawn, add and remove components and change resources, e.g. while iterating query
+/// results. The queue is applied by `World::apply_commands` or `World::end_frame`, entity commands
+/// first and resource commands last. There's no `Commands::apply`: applying needs the world
+/// mutably, which the borrow held by `Commands` rules out for as long as it's alive.
+pub struct Commands<'a, M: Mask = u64> {
+    queue: &'a RefCell<CommandQueue<M>>,
+}
+
+impl<M: Mask> Clone for Commands<'_, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Mask> Copy for Commands<'_, M> {}
+
+impl<M: Mask> fmt::Debug for Commands<'_, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Commands").finish_non_exhaustive()
+    }
+}
+
+impl<'a, M: Mask> Commands<'a, M> {
+    pub(crate) fn new(queue: &'a RefCell<CommandQueue<M>>) -> Self {
+        Self { queue }
+    }
+
+    /// Queues spawning an entity with the components `build` adds to it.
+    pub fn spawn(&self, build: impl FnOnce(&mut EntityBuilder<M>) + 'static) {
+        self.push(move |world| {
+            let mut builder = EntityBuilder::default();
+            build(&mut builder);
+            builder.spawn(&mut world.entities).map(drop)
+        });
+    }
+
+    pub fn despawn(&self, id: impl EntityId + 'static) {
+        self.push(move |world| world.delete_entity_by_id(id));
+    }
+
+    pub fn add_component(&self, id: impl EntityId + 'static, component: impl Any) {
+        self.push(move |world| world.add_component_to_entity_by_id(id, component));
+    }
+
+    pub fn remove_component<T: Any>(&self, id: impl EntityId + 'static) {
+        self.push(move |world| world.delete_component_by_entity_id::<T>(id));
+    }
+
+    pub fn insert_resource(&self, resource: impl Any) {
+        self.queue
+            .borrow_mut()
+            .resources
+            .push(Box::new(move |world| {
+                world.add_resource(resource);
+                Ok(())
+            }));
+    }
+
+    pub fn remove_resource<T: Any>(&self) {
+        self.queue.borrow_mut().resources.push(Box::new(|world| {
+            world.remove_resource::<T>();
+            Ok(())
+        }));
+    }
+
+    fn push(&self, command: impl FnOnce(&mut WorldGeneric<M>) -> Result<()> + 'static) {
+        self.queue.borrow_mut().entities.push(Box::new(command));
+    }
+}
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq, Eq)]
 pub enum CustomError {
     #[error("Attempting to add component to an entity without creating component first")]
     CreateComponentNeverCalled,
@@ -10,4 +10,71 @@ pub enum CustomError {
 
     #[error("Attempted to reference an entity that doesn't exist")]
     EntityDoesNotExist,
+
+    #[error("Attempted to swap buffers of a component that wasn't registered as buffered")]
+    ComponentNotBuffered,
+
+    #[error("Component bit assignment is invalid: {0}")]
+    InvalidBitAssignment(String),
+
+    #[error("Expected {expected} values to match the ids, but got {actual}")]
+    ColumnLengthMismatch { expected: usize, actual: usize },
+
+    #[error("Attempted to add a component the entity already has")]
+    ComponentAlreadyPresent,
+
+    #[error("Attempted to use an index for a component that isn't indexed")]
+    ComponentNotIndexed,
+
+    #[error("Component {type_name} of entity {entity} is already borrowed")]
+    ComponentBorrowConflict {
+        entity: usize,
+        type_name: &'static str,
+    },
+
+    #[error("Attempted to use an entity id checked before an entity was deleted")]
+    StaleEntityId,
+
+    #[error("Component {0} can't be snapshotted because it wasn't registered as cloneable")]
+    ComponentNotCloneable(&'static str),
+
+    #[error("Attempted to use a resource that wasn't added")]
+    ResourceNotFound,
+
+    #[error("Attempted to spawn unknown prototype {0:?}")]
+    UnknownPrototype(String),
+
+    #[error("Component tag {0:?} is already used by another component")]
+    DuplicateComponentTag(&'static str),
+
+    #[error("Component {0} isn't required by the query")]
+    ComponentNotInQuery(&'static str),
+
+    #[error("Expected exactly one matching entity, but found {found}")]
+    ExpectedSingleEntity { found: usize },
+
+    #[error("Attempted to register more than {0} components, the width of the entity mask")]
+    TooManyComponents(u32),
+
+    #[error("Timesteps must be finite, with a non-negative delta and a positive fixed interval")]
+    InvalidTimestep,
+
+    #[error("Spatial index cells must have a positive, finite size")]
+    InvalidCellSize,
+
+    #[error("Expected two different component types, but got {0} twice")]
+    DuplicateComponentType(&'static str),
+
+    #[error("Query ran while {0} components from the previous run are still held")]
+    QueryResultsHeld(usize),
+}
+
+/// How fallible operations on the world report failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Panic with the error message. Handy for a fast development loop.
+    Panic,
+    /// Return the error as an `Err`.
+    #[default]
+    Result,
 }
@@ -1,292 +1,2900 @@
+pub mod builder;
+pub mod bundle;
+pub mod cached_query;
+pub mod dirty;
+pub mod history;
+pub mod indexes;
+pub mod leaks;
+pub mod mask;
 pub mod query;
+pub mod relation;
+pub mod schema;
+pub mod sorted;
+pub mod spatial;
+pub mod storage;
 
-use crate::custom_errors::CustomError;
+use crate::custom_errors::{CustomError, ErrorPolicy};
+use crate::entities::bundle::Bundle;
+use crate::entities::dirty::{DirtyRegion, Region};
+use crate::entities::history::{cloner, Cloner, History, Snapshot};
+use crate::entities::indexes::{Backrefs, Indexes};
+use crate::entities::leaks::{LeakDetector, LeakPolicy};
+use crate::entities::mask::Mask;
+use crate::entities::relation::Relation;
+use crate::entities::schema::{ComponentSchema, Reflect};
+use crate::entities::sorted::{SortedIndex, ValueIndex};
+use crate::entities::spatial::SpatialIndex;
+use crate::entities::storage::{ComponentStorage, DenseStorage};
 use eyre::Result;
-use std::any::{Any, TypeId};
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::any::{type_name, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::rc::Rc;
 
 type ComponentList = Vec<Option<Rc<RefCell<dyn Any>>>>;
 
+/// Which copy of a buffered component a query should read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffer {
+    Current,
+    Previous,
+}
+
+/// How `merge` places source entities whose ids may collide with destination entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Give every source entity a fresh id.
+    #[default]
+    Remap,
+    /// Keep source ids, replacing any destination entity already there.
+    Overwrite,
+    /// Keep source ids, leaving out source entities whose id is taken in the destination.
+    Skip,
+}
+
+/// What `delete_component_by_entity_id` does with the removed value of a component type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemovalPolicy {
+    /// Drop the value, freeing its memory.
+    #[default]
+    Drop,
+    /// Keep the value in storage, hidden, so `reinstate_component` can bring it back without
+    /// building a new one. Deleting the entity, or removing its last component, still drops it.
+    Retain,
+}
+
+/// A change to which entities exist or which components they have. An entity is spawned when it
+/// gains its first component and despawned when it loses its last one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructuralEvent {
+    EntitySpawned(usize),
+    EntityDespawned(usize),
+    ComponentAdded(usize, TypeId),
+    ComponentRemoved(usize, TypeId),
+}
+
+/// An entity id stamped with the deletion epoch it was checked in. It only resolves back to the
+/// id as long as no entity has been deleted since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckedId {
+    id: usize,
+    epoch: u64,
+}
+
+impl CheckedId {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+/// An entity id paired with the generation of its slot. Deleting the entity moves the slot to the
+/// next generation, so a handle kept past that is rejected instead of silently referring to the
+/// slot's next occupant. Removing an entity's last component frees its slot the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity {
+    index: usize,
+    generation: u32,
+}
+
+impl Entity {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// What the id-taking methods accept: a bare `usize` id, which only has to be in range, or an
+/// `Entity`, which also has to be of its slot's current generation.
+pub trait EntityId: Copy {
+    /// The slot this id refers to, or `CustomError::EntityDoesNotExist` if it doesn't refer to
+    /// one anymore.
+    fn resolve_in<M: Mask>(self, entities: &Entities<M>) -> Result<usize>;
+}
+
+impl EntityId for usize {
+    fn resolve_in<M: Mask>(self, entities: &Entities<M>) -> Result<usize> {
+        entities.check_entity(self)?;
+        Ok(self)
+    }
+}
+
+impl EntityId for Entity {
+    fn resolve_in<M: Mask>(self, entities: &Entities<M>) -> Result<usize> {
+        if entities.generations.get(self.index) != Some(&self.generation) {
+            return entities.fail(CustomError::EntityDoesNotExist);
+        }
+        Ok(self.index)
+    }
+}
+
 #[derive(Default, Debug)]
-pub struct Entities {
-    components: HashMap<TypeId, ComponentList>,
-    bit_masks: HashMap<TypeId, u32>,
-    map: Vec<u32>,
+pub struct Entities<M: Mask = u64> {
+    components: HashMap<TypeId, Box<dyn ComponentStorage>>,
+    previous: HashMap<TypeId, ComponentList>,
+    bit_masks: HashMap<TypeId, M>,
+    type_names: HashMap<TypeId, &'static str>,
+    map: Vec<M>,
+    generations: Vec<u32>,
     first_empty_index: usize,
+    error_policy: ErrorPolicy,
+    indexes: RefCell<Indexes>,
+    structural_tick: u64,
+    batching: bool,
+    batch_changed: bool,
+    tags: HashMap<String, HashSet<usize>>,
+    epoch: u64,
+    cloners: HashMap<TypeId, Cloner>,
+    history: History,
+    reflection: HashMap<TypeId, Reflect>,
+    despawn_log: Vec<(usize, String)>,
+    despawn_log_cap: usize,
+    added: HashMap<TypeId, HashSet<usize>>,
+    changed: RefCell<HashMap<TypeId, HashSet<usize>>>,
+    populated: HashMap<TypeId, usize>,
+    gained: HashMap<TypeId, Vec<usize>>,
+    userdata: HashMap<usize, u64>,
+    structural_events: Option<Vec<StructuralEvent>>,
+    component_tags: HashMap<TypeId, &'static str>,
+    tagged_components: HashMap<&'static str, TypeId>,
+    spawned_at: Vec<u64>,
+    spawn_count: u64,
+    removal: HashMap<TypeId, RemovalPolicy>,
+    leak_detector: Option<LeakDetector>,
 }
 
-impl Entities {
-    pub fn register_component<T: Any>(&mut self) {
-        self.components
-            .entry(TypeId::of::<T>())
-            .or_insert_with(Vec::new);
-        self.bit_masks
-            .entry(TypeId::of::<T>())
-            .or_insert(1 << (self.components.len() - 1));
-    }
+impl<M: Mask> Entities<M> {
+    pub fn register_component<T: Any>(&mut self) -> Result<()> {
+        self.register_component_with_storage::<T>(DenseStorage::default())
+    }
+
+    /// Registers `T` with the given storage. Registering an already registered component keeps
+    /// its existing storage. Fails with `CustomError::TooManyComponents` once every bit of the mask
+    /// is taken.
+    pub fn register_component_with_storage<T: Any>(
+        &mut self,
+        storage: impl ComponentStorage + 'static,
+    ) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        if !self.components.contains_key(&type_id) && self.components.len() >= M::BITS as usize {
+            return self.fail(CustomError::TooManyComponents(M::BITS));
+        }
+        self.components
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(storage));
+        self.bit_masks
+            .entry(TypeId::of::<T>())
+            .or_insert(M::bit(self.components.len() as u32 - 1));
+        self.type_names
+            .entry(TypeId::of::<T>())
+            .or_insert_with(type_name::<T>);
+        #[cfg(debug_assertions)]
+        if let Err(error) = self.verify_bit_assignment() {
+            panic!("{}", error);
+        }
+        Ok(())
+    }
+
+    /// Registers `T` under a stable `tag` that, unlike its type name, stays the same across
+    /// compilers. Registering `T` again with another tag replaces its tag.
+    pub fn register_component_named<T: Any>(&mut self, tag: &'static str) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        if self
+            .tagged_components
+            .get(tag)
+            .is_some_and(|&tagged| tagged != type_id)
+        {
+            return self.fail(CustomError::DuplicateComponentTag(tag));
+        }
+        self.register_component::<T>()?;
+        if let Some(previous) = self.component_tags.insert(type_id, tag) {
+            self.tagged_components.remove(previous);
+        }
+        self.tagged_components.insert(tag, type_id);
+        Ok(())
+    }
+
+    pub fn component_tag<T: Any>(&self) -> Option<&'static str> {
+        self.component_tags.get(&TypeId::of::<T>()).copied()
+    }
+
+    pub fn component_by_tag(&self, tag: &str) -> Option<TypeId> {
+        self.tagged_components.get(tag).copied()
+    }
+
+    /// Registers `T`, choosing what removing it from an entity does with its value.
+    pub fn register_component_removal<T: Any>(&mut self, policy: RemovalPolicy) -> Result<()> {
+        self.register_component::<T>()?;
+        self.removal.insert(TypeId::of::<T>(), policy);
+        Ok(())
+    }
+
+    fn removal_policy(&self, type_id: &TypeId) -> RemovalPolicy {
+        self.removal.get(type_id).copied().unwrap_or_default()
+    }
+
+    /// Checks that every registered component owns a single bit that no other component shares.
+    pub fn verify_bit_assignment(&self) -> Result<()> {
+        let mut owners = BTreeMap::<M, Vec<&str>>::new();
+        for (type_id, &mask) in &self.bit_masks {
+            owners
+                .entry(mask)
+                .or_default()
+                .push(self.type_names[type_id]);
+        }
+        let problems = owners
+            .into_iter()
+            .filter_map(|(mask, mut names)| {
+                names.sort_unstable();
+                if mask.count_ones() != 1 {
+                    Some(format!("{} have mask {:#b}", names.join(", "), mask))
+                } else if names.len() > 1 {
+                    Some(format!("{} share bit {:#b}", names.join(", "), mask))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            self.fail(CustomError::InvalidBitAssignment(problems.join("; ")))
+        }
+    }
+
+    /// Registers `T` so that the owner of any stored `T` can be looked up from the component cell
+    /// itself with `owner_of_component`.
+    pub fn register_component_with_backref<T: Any>(&mut self) -> Result<()> {
+        self.register_component::<T>()?;
+        let type_id = TypeId::of::<T>();
+        if !self.indexes.get_mut().backrefs.contains_key(&type_id) {
+            let mut backrefs = Backrefs::default();
+            for (id, component) in self.column(&type_id) {
+                backrefs.insert(id, &*component.borrow());
+            }
+            self.indexes.get_mut().backrefs.insert(type_id, backrefs);
+        }
+        Ok(())
+    }
+
+    /// Registers `T` along with a way to clone its values, which lets entities carrying it be
+    /// snapshotted for undo.
+    pub fn register_cloneable_component<T: Any + Clone>(&mut self) -> Result<()> {
+        self.register_component::<T>()?;
+        self.cloners.insert(TypeId::of::<T>(), cloner::<T>);
+        Ok(())
+    }
+
+    pub fn register_reflection<T: Any>(&mut self, reflect: Reflect) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        self.reflection.insert(type_id, reflect);
+        Ok(())
+    }
+
+    /// Describes every registered component, ordered by bit.
+    pub fn schema(&self) -> Vec<ComponentSchema> {
+        let mut schema = self
+            .bit_masks
+            .iter()
+            .map(|(type_id, &mask)| ComponentSchema {
+                type_name: self.type_names[type_id],
+                bit_index: mask.trailing_zeros(),
+                entity_count: self.map.iter().filter(|&&map| map & mask == mask).count(),
+                fields: self
+                    .reflection
+                    .get(type_id)
+                    .map(|reflect| reflect())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        schema.sort_by_key(|component| component.bit_index);
+        schema
+    }
+
+    pub fn owner_of_component<T: Any>(&self, component: &Rc<RefCell<dyn Any>>) -> Option<usize> {
+        let indexes = self.indexes.borrow();
+        let backrefs = indexes.backrefs.get(&TypeId::of::<T>())?;
+        backrefs.owner(component.as_ptr())
+    }
+
+    pub fn register_buffered_component<T: Any>(&mut self) -> Result<()> {
+        self.register_component::<T>()?;
+        let len = self.map.len();
+        self.previous
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| vec![None; len]);
+        Ok(())
+    }
+
+    pub fn swap_buffers<T: Any + Clone>(&mut self) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.registered_bitmask(&type_id)?;
+        if !self.previous.contains_key(&type_id) {
+            return self.fail(CustomError::ComponentNotBuffered);
+        }
+        let current = self.components.get(&type_id).unwrap();
+        let previous = self.previous.get_mut(&type_id).unwrap();
+        for (index, slot) in previous.iter_mut().enumerate() {
+            *slot = match current.get(index) {
+                Some(component) if self.map[index] & mask == mask => {
+                    let value = component.borrow().downcast_ref::<T>().unwrap().clone();
+                    Some(Rc::new(RefCell::new(value)))
+                }
+                _ => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Reads the previous copy of a buffered component, falling back to the current one for
+    /// entities whose buffers haven't been swapped yet.
+    pub(crate) fn get_buffered(
+        &self,
+        type_id: &TypeId,
+        index: usize,
+        buffer: Buffer,
+    ) -> Option<&Rc<RefCell<dyn Any>>> {
+        let previous = match buffer {
+            Buffer::Previous => self
+                .previous
+                .get(type_id)
+                .and_then(|previous| previous[index].as_ref()),
+            Buffer::Current => None,
+        };
+        previous.or_else(|| self.components.get(type_id)?.get(index))
+    }
+
+    pub fn create_entity(&mut self) -> &mut Self {
+        if let Some((index, _)) = self
+            .map
+            .iter()
+            .enumerate()
+            .find(|(_, mask)| **mask == M::ZERO)
+        {
+            self.first_empty_index = index;
+        } else {
+            self.push_slot();
+            self.first_empty_index = self.map.len() - 1;
+        }
+        self
+    }
+
+    /// Reserves `count` entities at once, reusing free slots before growing, and returns their ids.
+    /// Like with `create_entity`, a slot counts as free again until it gets a component, so add
+    /// components to the ids before creating more entities. `with_component` carries on with the
+    /// last id, the same as after `create_entity`.
+    pub fn create_entities(&mut self, count: usize) -> Vec<usize> {
+        let mut ids = self
+            .map
+            .iter()
+            .enumerate()
+            .filter(|(_, mask)| **mask == M::ZERO)
+            .map(|(index, _)| index)
+            .take(count)
+            .collect::<Vec<_>>();
+        while ids.len() < count {
+            self.push_slot();
+            ids.push(self.map.len() - 1);
+        }
+        if let Some(&last) = ids.last() {
+            self.first_empty_index = last;
+        }
+        ids
+    }
+
+    /// The handle of the entity `create_entity` started, to keep instead of its bare id.
+    pub fn entity(&self) -> Entity {
+        Entity {
+            index: self.first_empty_index,
+            generation: self
+                .generations
+                .get(self.first_empty_index)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The handle of the entity currently in slot `id`.
+    pub fn entity_at(&self, id: usize) -> Result<Entity> {
+        self.check_entity(id)?;
+        Ok(Entity {
+            index: id,
+            generation: self.generations[id],
+        })
+    }
+
+    pub fn with_component(&mut self, component: impl Any) -> Result<&mut Self> {
+        let type_id = &component.type_id();
+        let index = self.first_empty_index;
+        let bitmask = self.registered_bitmask(type_id)?;
+        if index >= self.map.len() {
+            return self.fail(CustomError::CreateComponentNeverCalled);
+        }
+        self.insert(index, *type_id, bitmask, Rc::new(RefCell::new(component)));
+        Ok(self)
+    }
+
+    /// Adds every component of `bundle`, a tuple of components. Fails without adding any of them
+    /// if one isn't registered.
+    pub fn with_bundle<B: Bundle>(&mut self, bundle: B) -> Result<&mut Self> {
+        for type_id in B::type_ids() {
+            self.registered_bitmask(&type_id)?;
+        }
+        bundle.add_to(self)?;
+        Ok(self)
+    }
+
+    /// Like `with_component`, but refuses to overwrite a component the entity already has.
+    pub fn try_with_component(&mut self, component: impl Any) -> Result<&mut Self> {
+        let bitmask = self.registered_bitmask(&component.type_id())?;
+        let index = self.first_empty_index;
+        if self
+            .map
+            .get(index)
+            .is_some_and(|&map| map & bitmask == bitmask)
+        {
+            return self.fail(CustomError::ComponentAlreadyPresent);
+        }
+        self.with_component(component)
+    }
+
+    pub fn get_bitmask(&self, type_id: &TypeId) -> Option<M> {
+        self.bit_masks.get(type_id).copied()
+    }
+
+    /// Every bit owned by a registered component.
+    pub(crate) fn registered_bits(&self) -> M {
+        self.bit_masks
+            .values()
+            .fold(M::ZERO, |bits, &mask| bits | mask)
+    }
+
+    pub fn mask_of<T: Any>(&self) -> Result<M> {
+        self.registered_bitmask(&TypeId::of::<T>())
+    }
+
+    /// How many live entities there are. An entity that lost its last component counts as gone,
+    /// the same as `create_entity` treats its slot as free for reuse.
+    pub fn entity_count(&self) -> usize {
+        self.map.iter().filter(|&&map| map != M::ZERO).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.iter().all(|&map| map == M::ZERO)
+    }
+
+    /// Lazily yields the handle of every live entity, in id order.
+    pub fn iter_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.map
+            .iter()
+            .zip(&self.generations)
+            .enumerate()
+            .filter(|(_, (&map, _))| map != M::ZERO)
+            .map(|(index, (_, &generation))| Entity { index, generation })
+    }
+
+    /// How many entities currently hold component `T`. Kept up to date as components come and go,
+    /// so this doesn't scan.
+    pub fn component_count<T: Any>(&self) -> Result<usize> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        Ok(self.populated(&type_id))
+    }
+
+    /// Turns the query borrow leak detector on with the given policy, or off with `None`. It only
+    /// runs in debug builds.
+    pub fn detect_query_leaks(&mut self, policy: Option<LeakPolicy>) {
+        self.leak_detector = policy.map(LeakDetector::new);
+    }
+
+    /// The enabled leak detector, never in release builds.
+    pub(crate) fn leak_detector(&self) -> Option<&LeakDetector> {
+        self.leak_detector
+            .as_ref()
+            .filter(|_| cfg!(debug_assertions))
+    }
+
+    /// How many query runs found results of the previous run still held.
+    pub fn query_leaks_detected(&self) -> usize {
+        self.leak_detector
+            .as_ref()
+            .map_or(0, LeakDetector::detected)
+    }
+
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    /// Surfaces `error` according to the configured `ErrorPolicy`.
+    pub(crate) fn fail<T>(&self, error: CustomError) -> Result<T> {
+        match self.error_policy {
+            ErrorPolicy::Panic => panic!("{}", error),
+            ErrorPolicy::Result => Err(error.into()),
+        }
+    }
+
+    pub(crate) fn registered_bitmask(&self, type_id: &TypeId) -> Result<M> {
+        match self.get_bitmask(type_id) {
+            None => self.fail(CustomError::ComponentNotRegistered),
+            Some(bitmask) => Ok(bitmask),
+        }
+    }
+
+    fn check_entity(&self, id: usize) -> Result<()> {
+        if id < self.map.len() {
+            Ok(())
+        } else {
+            self.fail(CustomError::EntityDoesNotExist)
+        }
+    }
+
+    /// The stored cell of component `type_id` for entity `id`, if the entity currently has it.
+    pub(crate) fn stored(&self, id: usize, type_id: &TypeId) -> Option<&Rc<RefCell<dyn Any>>> {
+        let mask = self.get_bitmask(type_id)?;
+        if *self.map.get(id)? & mask != mask {
+            return None;
+        }
+        self.components[type_id].get(id)
+    }
+
+    /// Whether entity `id` has a `T`. `false` if the entity doesn't exist or `T` isn't registered.
+    pub fn has_component<T: Any>(&self, id: usize) -> bool {
+        match (self.get_bitmask(&TypeId::of::<T>()), self.map.get(id)) {
+            (Some(mask), Some(&map)) => map & mask == mask,
+            _ => false,
+        }
+    }
+
+    /// Returns the cell holding entity `id`'s component `T`, or `None` if the entity doesn't
+    /// exist, doesn't have one, or `T` isn't registered.
+    pub fn get_component<T: Any>(&self, id: usize) -> Option<Rc<RefCell<dyn Any>>> {
+        self.stored(id, &TypeId::of::<T>()).cloned()
+    }
+
+    pub fn get_component_or<T: Any + Clone>(&self, id: usize, default: T) -> Result<T> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        self.check_entity(id)?;
+        Ok(match self.stored(id, &type_id) {
+            None => default,
+            Some(component) => component.borrow().downcast_ref::<T>().unwrap().clone(),
+        })
+    }
+
+    /// Borrows entity `id`'s component `T`, or `None` if the entity doesn't have one. A component
+    /// that is already mutably borrowed yields `ComponentBorrowConflict` instead of panicking.
+    pub fn try_borrow_component<T: Any>(&self, id: usize) -> Result<Option<Ref<'_, T>>> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        self.check_entity(id)?;
+        match self
+            .stored(id, &type_id)
+            .map(|component| component.try_borrow())
+        {
+            None => Ok(None),
+            Some(Err(_)) => self.borrow_conflict::<T, _>(id),
+            Some(Ok(component)) => Ok(Some(Ref::map(component, |c| {
+                c.downcast_ref::<T>().unwrap()
+            }))),
+        }
+    }
+
+    /// The mutable counterpart of `try_borrow_component`.
+    pub fn try_borrow_component_mut<T: Any>(&self, id: usize) -> Result<Option<RefMut<'_, T>>> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        self.check_entity(id)?;
+        match self
+            .stored(id, &type_id)
+            .map(|component| component.try_borrow_mut())
+        {
+            None => Ok(None),
+            Some(Err(_)) => self.borrow_conflict::<T, _>(id),
+            Some(Ok(component)) => Ok(Some(RefMut::map(component, |c| {
+                c.downcast_mut::<T>().unwrap()
+            }))),
+        }
+    }
+
+    fn borrow_conflict<T: Any, R>(&self, entity: usize) -> Result<R> {
+        self.fail(CustomError::ComponentBorrowConflict {
+            entity,
+            type_name: type_name::<T>(),
+        })
+    }
+
+    pub fn entities_missing<Have: Any, Missing: Any>(&self) -> Result<Vec<usize>> {
+        let have = self.registered_bitmask(&TypeId::of::<Have>())?;
+        let missing = self.registered_bitmask(&TypeId::of::<Missing>())?;
+        Ok(self
+            .map
+            .iter()
+            .enumerate()
+            .filter(|(_, &entity_map)| entity_map & have == have && entity_map & missing == M::ZERO)
+            .map(|(index, _)| index)
+            .collect())
+    }
+
+    pub fn matching_archetype_of(&self, template_id: usize) -> Result<Vec<usize>> {
+        self.check_entity(template_id)?;
+        let template = self.map[template_id];
+        Ok(self
+            .map
+            .iter()
+            .enumerate()
+            .filter(|(_, &entity_map)| entity_map != M::ZERO && entity_map & template == template)
+            .map(|(index, _)| index)
+            .collect())
+    }
+
+    pub fn archetypes(&self) -> HashMap<M, usize> {
+        let mut archetypes = HashMap::new();
+        for &entity_map in self.map.iter().filter(|&&entity_map| entity_map != M::ZERO) {
+            *archetypes.entry(entity_map).or_insert(0) += 1;
+        }
+        archetypes
+    }
+
+    pub fn delete_component_by_entity_id<T: Any>(&mut self, id: impl EntityId) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.registered_bitmask(&type_id)?;
+        let id = id.resolve_in(self)?;
+        if let Some(component) = self.remove(id, &type_id, mask) {
+            let alive = self.map[id] != M::ZERO;
+            if alive && self.removal_policy(&type_id) == RemovalPolicy::Retain {
+                self.components
+                    .get_mut(&type_id)
+                    .unwrap()
+                    .insert(id, component);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives entity `id` back the `T` value retained when it was removed under
+    /// `RemovalPolicy::Retain`. Returns whether the entity has a `T` afterwards.
+    pub fn reinstate_component<T: Any>(&mut self, id: usize) -> Result<bool> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.registered_bitmask(&type_id)?;
+        self.check_entity(id)?;
+        if self.map[id] & mask == mask {
+            return Ok(true);
+        }
+        match self.components[&type_id].get(id).cloned() {
+            None => Ok(false),
+            Some(component) => {
+                self.insert(id, type_id, mask, component);
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn add_component_by_entity_id(
+        &mut self,
+        id: impl EntityId,
+        component: impl Any,
+    ) -> Result<()> {
+        let type_id = component.type_id();
+        let mask = self.registered_bitmask(&type_id)?;
+        let id = id.resolve_in(self)?;
+        self.insert(id, type_id, mask, Rc::new(RefCell::new(component)));
+        Ok(())
+    }
+
+    /// Sets entity `id`'s component `T` to `value`. A `T` the entity already has is overwritten in
+    /// its existing cell rather than replaced by a new one, so clones of the cell held elsewhere
+    /// see the new value. Otherwise the component is added.
+    pub fn set_component<T: Any>(&mut self, id: impl EntityId, value: T) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.registered_bitmask(&type_id)?;
+        let id = id.resolve_in(self)?;
+        let component = match self.stored(id, &type_id) {
+            None => {
+                self.insert(id, type_id, mask, Rc::new(RefCell::new(value)));
+                return Ok(());
+            }
+            Some(component) => component.clone(),
+        };
+        match component.try_borrow_mut() {
+            Err(_) => return self.borrow_conflict::<T, _>(id),
+            Ok(mut current) => *current.downcast_mut::<T>().unwrap() = value,
+        }
+        self.mark_changed(id, &type_id, &*component.borrow());
+        Ok(())
+    }
+
+    /// Deletes entity `id` with all its components and moves its slot to the next generation.
+    pub fn delete_by_id(&mut self, id: impl EntityId) -> Result<()> {
+        let id = id.resolve_in(self)?;
+        self.delete_slot(id);
+        Ok(())
+    }
+
+    /// Deletes every entity, keeping registered components and their storage settings, so a new
+    /// level can start without registering everything again.
+    pub fn clear_entities(&mut self) {
+        let was_batching = self.begin_batch();
+        for id in 0..self.map.len() {
+            self.delete_slot(id);
+        }
+        self.first_empty_index = 0;
+        self.end_batch(was_batching);
+    }
+
+    fn delete_slot(&mut self, id: usize) {
+        let occupied = self.map[id] != M::ZERO;
+        self.remove_all(id);
+        if !occupied {
+            self.free_slot(id);
+        }
+        self.epoch += 1;
+    }
+
+    /// Forgets everything attached to slot `id` and moves it to the next generation, once its
+    /// entity is gone either way: deleted, or stripped of its last component.
+    fn free_slot(&mut self, id: usize) {
+        for storage in self.components.values_mut() {
+            storage.remove(id);
+        }
+        self.previous.values_mut().for_each(|v| v[id] = None);
+        self.tags.values_mut().for_each(|tagged| {
+            tagged.remove(&id);
+        });
+        self.tags.retain(|_, tagged| !tagged.is_empty());
+        self.userdata.remove(&id);
+        self.generations[id] = self.generations[id].wrapping_add(1);
+    }
+
+    /// Moves every entity of `other`, with its components and tags, into this world, placing them
+    /// according to `strategy`. Returns where each moved entity ended up, keyed by its id in
+    /// `other`. Every component `other` uses must be registered here too.
+    pub fn merge(
+        &mut self,
+        other: Entities<M>,
+        strategy: MergeStrategy,
+    ) -> Result<HashMap<usize, usize>> {
+        let mut moved = BTreeMap::<usize, Vec<(TypeId, Rc<RefCell<dyn Any>>)>>::new();
+        for (type_id, storage) in &other.components {
+            let mask = other.bit_masks[type_id];
+            for (id, component) in storage.iter() {
+                if other.map[id] & mask == mask {
+                    self.registered_bitmask(type_id)?;
+                    moved
+                        .entry(id)
+                        .or_default()
+                        .push((*type_id, component.clone()));
+                }
+            }
+        }
+
+        let was_batching = self.begin_batch();
+        let mut placed = HashMap::new();
+        for (source, components) in moved {
+            let destination = match strategy {
+                MergeStrategy::Remap => self.create_entity().first_empty_index,
+                MergeStrategy::Overwrite | MergeStrategy::Skip => {
+                    while self.map.len() <= source {
+                        self.push_slot();
+                    }
+                    if self.map[source] != M::ZERO {
+                        if strategy == MergeStrategy::Skip {
+                            continue;
+                        }
+                        self.delete_by_id(source)?;
+                    }
+                    source
+                }
+            };
+            for (type_id, component) in components {
+                let mask = self.bit_masks[&type_id];
+                self.insert(destination, type_id, mask, component);
+            }
+            placed.insert(source, destination);
+        }
+        for (tag, ids) in other.tags {
+            for id in ids {
+                if let Some(&destination) = placed.get(&id) {
+                    self.tags
+                        .entry(tag.clone())
+                        .or_default()
+                        .insert(destination);
+                }
+            }
+        }
+        self.end_batch(was_batching);
+        Ok(placed)
+    }
+
+    /// Deletes entity `id` like `delete_by_id`, recording `reason` in the despawn log if it's
+    /// enabled.
+    pub fn despawn_with_reason(&mut self, id: usize, reason: &str) -> Result<()> {
+        self.delete_by_id(id)?;
+        if self.despawn_log_cap > 0 {
+            if self.despawn_log.len() == self.despawn_log_cap {
+                self.despawn_log.remove(0);
+            }
+            self.despawn_log.push((id, reason.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Keeps the `cap` most recent despawn reasons. The log is disabled with a cap of 0, which is
+    /// the default.
+    pub fn set_despawn_log_cap(&mut self, cap: usize) {
+        self.despawn_log_cap = cap;
+        let excess = self.despawn_log.len().saturating_sub(cap);
+        self.despawn_log.drain(..excess);
+    }
+
+    pub fn despawn_log(&self) -> &[(usize, String)] {
+        &self.despawn_log
+    }
+
+    /// Calls `f` with every entity holding a `T` and a mutable reference to it, straight from
+    /// storage. Each visited component is marked changed for `changed_or_added`; indexes are only
+    /// updated when one is derived from `T`.
+    pub fn each_mut<T: Any>(&mut self, mut f: impl FnMut(usize, &mut T)) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.registered_bitmask(&type_id)?;
+        let indexes = self.indexes.get_mut();
+        let indexed = indexes.covers(&type_id);
+        let changed = self.changed.get_mut().entry(type_id).or_default();
+        for (id, component) in self.components[&type_id].iter() {
+            if self.map[id] & mask == mask {
+                let mut component = component.borrow_mut();
+                f(id, component.downcast_mut::<T>().unwrap());
+                if indexed {
+                    indexes.insert(id, &type_id, &*component);
+                }
+                changed.insert(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces every `T` with `f` of its value. All new values are computed before any is written
+    /// back, so `f` only ever sees the values from before the update, even when it reads other
+    /// entities' components.
+    pub fn map_component<T: Any>(&mut self, f: impl Fn(&T) -> T) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        let mut staged = self
+            .column(&type_id)
+            .map(|(_, component)| f(component.borrow().downcast_ref::<T>().unwrap()))
+            .collect::<Vec<_>>()
+            .into_iter();
+        self.each_mut(|_, value: &mut T| *value = staged.next().unwrap())
+    }
+
+    /// Like `each_mut`, but through a shared borrow: a component that is already borrowed elsewhere
+    /// is skipped instead of panicking. Returns the ids that were skipped. Since the world isn't
+    /// borrowed mutably, the writes aren't seen by indexes or by `changed_or_added`.
+    pub fn each_mut_try<T: Any>(&self, mut f: impl FnMut(usize, &mut T)) -> Result<Vec<usize>> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        let mut skipped = vec![];
+        for (id, component) in self.column(&type_id) {
+            match component.try_borrow_mut() {
+                Ok(mut component) => f(id, component.downcast_mut::<T>().unwrap()),
+                Err(_) => skipped.push(id),
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Copies component `T` out of each of the given entities that has one, returning the ids that
+    /// were exported alongside a packed column of their values.
+    pub fn export_column<T: Any + Copy>(
+        &self,
+        ids: impl Iterator<Item = usize>,
+    ) -> Result<(Vec<usize>, Vec<T>)> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        Ok(ids
+            .filter_map(|id| {
+                let component = self.stored(id, &type_id)?;
+                let value = *component.borrow().downcast_ref::<T>().unwrap();
+                Some((id, value))
+            })
+            .unzip())
+    }
+
+    /// Copies every live `T` into a packed column paired with the ids of their entities, walking
+    /// the column directly. An unregistered `T` yields empty columns.
+    pub fn collect_copy<T: Any + Copy>(&self) -> (Vec<usize>, Vec<T>) {
+        let type_id = TypeId::of::<T>();
+        if !self.bit_masks.contains_key(&type_id) {
+            return (vec![], vec![]);
+        }
+        self.column(&type_id)
+            .map(|(id, component)| (id, *component.borrow().downcast_ref::<T>().unwrap()))
+            .unzip()
+    }
+
+    /// Writes `values[i]` into component `T` of entity `ids[i]`, updating existing components in
+    /// place and adding the component to entities that don't have it yet.
+    pub fn import_column<T: Any + Copy>(&mut self, ids: &[usize], values: &[T]) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        if ids.len() != values.len() {
+            return self.fail(CustomError::ColumnLengthMismatch {
+                expected: ids.len(),
+                actual: values.len(),
+            });
+        }
+        for (&id, &value) in ids.iter().zip(values) {
+            match self.stored(id, &type_id).cloned() {
+                None => self.add_component_by_entity_id(id, value)?,
+                Some(component) => {
+                    let mut component = component.borrow_mut();
+                    *component.downcast_mut::<T>().unwrap() = value;
+                    self.mark_changed(id, &type_id, &*component);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The live cells of component `type_id`, paired with their entity ids.
+    pub(crate) fn column(
+        &self,
+        type_id: &TypeId,
+    ) -> impl Iterator<Item = (usize, &Rc<RefCell<dyn Any>>)> + '_ {
+        let mask = self.bit_masks[type_id];
+        self.components[type_id]
+            .iter()
+            .filter(move |(id, _)| self.map[*id] & mask == mask)
+    }
+
+    /// Advances whenever a component is added to or removed from an entity, so caches derived
+    /// from entity signatures can tell when they are stale.
+    pub fn structural_tick(&self) -> u64 {
+        self.structural_tick
+    }
+
+    /// Holds back tick increments until the matching `end_batch`. Returns whether a batch was
+    /// already running, which must be handed back to `end_batch` so batches can nest.
+    pub fn begin_batch(&mut self) -> bool {
+        std::mem::replace(&mut self.batching, true)
+    }
+
+    pub fn end_batch(&mut self, was_batching: bool) {
+        if !was_batching {
+            self.batching = false;
+            if std::mem::take(&mut self.batch_changed) {
+                self.structural_tick += 1;
+            }
+        }
+    }
+
+    pub fn enable_spatial_index<Pos: Any>(
+        &mut self,
+        cell_size: f32,
+        extract: impl Fn(&Pos) -> (f32, f32) + 'static,
+    ) -> Result<()> {
+        self.registered_bitmask(&TypeId::of::<Pos>())?;
+        let index = match SpatialIndex::new(cell_size, extract) {
+            Ok(index) => index,
+            Err(error) => return self.fail(error),
+        };
+        self.indexes.get_mut().spatial = Some(index);
+        self.refresh_spatial_index();
+        Ok(())
+    }
+
+    /// Rebuckets every indexed entity. Insertions and removals keep the index up to date on their
+    /// own, but positions mutated in place through a `RefCell` need a refresh to be picked up.
+    pub fn refresh_spatial_index(&mut self) {
+        if let Some(mut index) = self.indexes.get_mut().spatial.take() {
+            index.clear();
+            for (id, component) in self.column(&index.type_id) {
+                index.insert(id, &*component.borrow());
+            }
+            self.indexes.get_mut().spatial = Some(index);
+        }
+    }
+
+    pub fn query_region(&self, min: (f32, f32), max: (f32, f32)) -> Vec<usize> {
+        match &self.indexes.borrow().spatial {
+            None => vec![],
+            Some(index) => index.query(min, max),
+        }
+    }
+
+    pub fn track_dirty_region<Pos: Any>(
+        &mut self,
+        extract: impl Fn(&Pos) -> (f32, f32) + 'static,
+    ) -> Result<()> {
+        let type_id = TypeId::of::<Pos>();
+        self.registered_bitmask(&type_id)?;
+        let mut dirty = DirtyRegion::new(extract);
+        for (id, component) in self.column(&type_id) {
+            dirty.track(id, &*component.borrow());
+        }
+        self.indexes.get_mut().dirty = Some(dirty);
+        Ok(())
+    }
+
+    pub fn dirty_region(&self) -> Option<Region> {
+        self.indexes.borrow().dirty.as_ref()?.region()
+    }
+
+    pub fn reset_dirty_region(&mut self) {
+        if let Some(dirty) = &mut self.indexes.get_mut().dirty {
+            dirty.reset();
+        }
+    }
+
+    pub fn track_relation<T: Any>(
+        &mut self,
+        extract: impl Fn(&T) -> usize + 'static,
+    ) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        let mut relation = Relation::new(extract);
+        for (id, component) in self.column(&type_id) {
+            relation.insert(id, &*component.borrow());
+        }
+        self.indexes.get_mut().relation = Some(relation);
+        Ok(())
+    }
+
+    pub fn related(&self, owner: usize) -> Vec<usize> {
+        match &self.indexes.borrow().relation {
+            None => vec![],
+            Some(relation) => relation.members(owner).to_vec(),
+        }
+    }
+
+    /// The owner entity `id` points at through the tracked relation, if any.
+    pub fn owner(&self, id: usize) -> Option<usize> {
+        self.indexes.borrow().relation.as_ref()?.owner(id)
+    }
+
+    pub fn index_component_sorted<T: Any + Ord + Clone>(&mut self) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        let mut index = SortedIndex::<T>::new();
+        for (id, component) in self.column(&type_id) {
+            index.insert(id, &*component.borrow());
+        }
+        self.indexes
+            .get_mut()
+            .sorted
+            .insert(type_id, Box::new(index));
+        Ok(())
+    }
+
+    pub fn query_range<T: Any + Ord + Clone>(&self, lo: T, hi: T) -> Result<Vec<usize>> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        match self.indexes.borrow().sorted.get(&type_id) {
+            None => self.fail(CustomError::ComponentNotIndexed),
+            Some(index) => {
+                let index = index.as_any().downcast_ref::<SortedIndex<T>>().unwrap();
+                Ok(index.range(lo, hi))
+            }
+        }
+    }
+
+    pub fn checked_id(&self, id: usize) -> CheckedId {
+        CheckedId {
+            id,
+            epoch: self.epoch,
+        }
+    }
+
+    /// Resolves `checked` back to its id, rejecting it if any entity was deleted since it was
+    /// checked.
+    pub fn resolve(&self, checked: CheckedId) -> Result<usize> {
+        if checked.epoch != self.epoch {
+            return self.fail(CustomError::StaleEntityId);
+        }
+        self.check_entity(checked.id)?;
+        Ok(checked.id)
+    }
+
+    pub fn add_tag(&mut self, id: usize, tag: &str) -> Result<()> {
+        self.check_entity(id)?;
+        self.tags.entry(tag.to_owned()).or_default().insert(id);
+        Ok(())
+    }
+
+    pub fn remove_tag(&mut self, id: usize, tag: &str) -> Result<()> {
+        self.check_entity(id)?;
+        if let Some(tagged) = self.tags.get_mut(tag) {
+            tagged.remove(&id);
+            if tagged.is_empty() {
+                self.tags.remove(tag);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_tag(&self, id: usize, tag: &str) -> bool {
+        self.tags
+            .get(tag)
+            .is_some_and(|tagged| tagged.contains(&id))
+    }
+
+    /// Attaches an opaque host handle to entity `id`, replacing any previous one. It lives outside
+    /// the component system and is dropped when the entity is deleted or loses its last component.
+    pub fn set_userdata(&mut self, id: usize, userdata: u64) -> Result<()> {
+        self.check_entity(id)?;
+        self.userdata.insert(id, userdata);
+        Ok(())
+    }
+
+    pub fn userdata(&self, id: usize) -> Option<u64> {
+        self.userdata.get(&id).copied()
+    }
+
+    /// Snapshots the world before an edit. Starting another edit before committing replaces the
+    /// open one.
+    pub fn begin_edit(&mut self) -> Result<()> {
+        self.history.open = Some(self.snapshot()?);
+        Ok(())
+    }
+
+    /// Makes the open edit undoable, discarding anything that could be redone.
+    pub fn commit_edit(&mut self) {
+        if let Some(snapshot) = self.history.open.take() {
+            self.history.push_undo(snapshot);
+            self.history.redo.clear();
+        }
+    }
+
+    /// Restores the state from before the last committed edit. Returns whether there was one.
+    pub fn undo(&mut self) -> Result<bool> {
+        let Some(snapshot) = self.history.undo.pop_back() else {
+            return Ok(false);
+        };
+        let current = match self.snapshot() {
+            Ok(current) => current,
+            Err(error) => {
+                self.history.undo.push_back(snapshot);
+                return Err(error);
+            }
+        };
+        self.history.redo.push(current);
+        self.restore(snapshot);
+        Ok(true)
+    }
+
+    /// Reapplies the last undone edit. Returns whether there was one.
+    pub fn redo(&mut self) -> Result<bool> {
+        let Some(snapshot) = self.history.redo.pop() else {
+            return Ok(false);
+        };
+        let current = match self.snapshot() {
+            Ok(current) => current,
+            Err(error) => {
+                self.history.redo.push(snapshot);
+                return Err(error);
+            }
+        };
+        self.history.push_undo(current);
+        self.restore(snapshot);
+        Ok(true)
+    }
+
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.history.depth = depth;
+        while self.history.undo.len() > depth {
+            self.history.undo.pop_front();
+        }
+    }
+
+    /// Deep copies every live component, failing if one of them has no registered cloner.
+    fn snapshot(&self) -> Result<Snapshot> {
+        let mut components = vec![];
+        for (type_id, storage) in &self.components {
+            let mask = self.bit_masks[type_id];
+            for (id, component) in storage.iter() {
+                if self.map[id] & mask != mask {
+                    continue;
+                }
+                match self.cloners.get(type_id) {
+                    None => {
+                        return self
+                            .fail(CustomError::ComponentNotCloneable(self.type_names[type_id]))
+                    }
+                    Some(clone) => components.push((id, *type_id, clone(&*component.borrow()))),
+                }
+            }
+        }
+        Ok(Snapshot {
+            components,
+            tags: self.tags.clone(),
+        })
+    }
+
+    /// Replaces every entity's components and tags with the snapshotted ones, as one structural
+    /// change. Outstanding `CheckedId`s are invalidated since entities may have disappeared.
+    fn restore(&mut self, snapshot: Snapshot) {
+        let was_batching = self.begin_batch();
+        let generations = self.generations.clone();
+        let userdata = std::mem::take(&mut self.userdata);
+        for id in 0..self.map.len() {
+            self.remove_all(id);
+        }
+        self.generations = generations;
+        self.userdata = userdata;
+        for (id, type_id, component) in snapshot.components {
+            let mask = self.bit_masks[&type_id];
+            self.insert(id, type_id, mask, component);
+        }
+        self.tags = snapshot.tags;
+        self.epoch += 1;
+        self.end_batch(was_batching);
+    }
+
+    /// Whether entity `id`'s `type_id` component was added, or changed through `each_mut`,
+    /// `import_column` or by replacing it, since trackers were last cleared.
+    pub(crate) fn changed_or_added(&self, id: usize, type_id: &TypeId) -> bool {
+        [&self.added, &*self.changed.borrow()]
+            .iter()
+            .any(|tracked| tracked.get(type_id).is_some_and(|ids| ids.contains(&id)))
+    }
+
+    /// Records that entity `id`'s `type_id` component was written in place, so derived indexes and
+    /// `changed_or_added` see the new value. Takes a shared borrow so queries can report writes.
+    pub(crate) fn mark_changed(&self, id: usize, type_id: &TypeId, component: &dyn Any) {
+        self.indexes.borrow_mut().insert(id, type_id, component);
+        self.changed
+            .borrow_mut()
+            .entry(*type_id)
+            .or_default()
+            .insert(id);
+    }
+
+    /// Starts collecting the entities that gain component `T`, to be drained with `take_gained`.
+    pub fn observe_added<T: Any>(&mut self) -> Result<()> {
+        let type_id = TypeId::of::<T>();
+        self.registered_bitmask(&type_id)?;
+        self.gained.entry(type_id).or_default();
+        Ok(())
+    }
+
+    /// The entities that gained observed component `type_id` since the last call, in id order.
+    pub fn take_gained(&mut self, type_id: &TypeId) -> Vec<usize> {
+        let mut gained = self
+            .gained
+            .get_mut(type_id)
+            .map(std::mem::take)
+            .unwrap_or_default();
+        gained.sort_unstable();
+        gained.dedup();
+        gained
+    }
+
+    /// Starts recording structural events, to be drained with `drain_structural_events`.
+    pub fn record_structural_events(&mut self) {
+        self.structural_events.get_or_insert_with(Vec::new);
+    }
+
+    /// The structural events since the last drain, oldest first. Empty unless recording was
+    /// started with `record_structural_events`.
+    pub fn drain_structural_events(&mut self) -> Vec<StructuralEvent> {
+        self.structural_events
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, event: StructuralEvent) {
+        if let Some(events) = &mut self.structural_events {
+            events.push(event);
+        }
+    }
+
+    /// Where entity `id` falls in spawn order. An entity is spawned when it gains its first
+    /// component, so an entity reusing a slot sorts after every entity spawned before it.
+    pub(crate) fn spawned_at(&self, id: usize) -> u64 {
+        self.spawned_at[id]
+    }
+
+    /// How many entities currently have component `type_id`.
+    pub(crate) fn populated(&self, type_id: &TypeId) -> usize {
+        self.populated.get(type_id).copied().unwrap_or_default()
+    }
+
+    /// Forgets which components were added or changed, typically once per frame.
+    pub fn clear_trackers(&mut self) {
+        self.added.clear();
+        self.changed.get_mut().clear();
+    }
+
+    /// Rebuilds every entity's signature from which storage slots are filled, repairing signatures
+    /// after the storage was edited directly. Derived indexes follow the repaired signatures.
+    pub fn recompute_masks(&mut self) {
+        let mut changes = vec![];
+        for (type_id, storage) in &self.components {
+            let mask = self.bit_masks[type_id];
+            for (id, &entity_map) in self.map.iter().enumerate() {
+                let stored = storage.get(id).is_some();
+                let retained = stored && self.removal_policy(type_id) == RemovalPolicy::Retain;
+                if stored != (entity_map & mask == mask) && !retained {
+                    changes.push((id, *type_id, mask, stored));
+                }
+            }
+        }
+        // Fill signatures in before clearing any, so no entity looks empty while it still has
+        // components to be added, which would free its slot.
+        changes.sort_by_key(|&(_, _, _, stored)| !stored);
+        let was_batching = self.begin_batch();
+        for (id, type_id, mask, stored) in changes {
+            if stored {
+                let component = self.components[&type_id].get(id).unwrap().clone();
+                self.insert(id, type_id, mask, component);
+            } else {
+                self.remove(id, &type_id, mask);
+            }
+        }
+        self.end_batch(was_batching);
+    }
+
+    /// Appends an empty entity slot.
+    fn push_slot(&mut self) {
+        self.previous.iter_mut().for_each(|(_, v)| v.push(None));
+        self.map.push(M::ZERO);
+        self.generations.push(0);
+        self.spawned_at.push(0);
+    }
+
+    /// Removes every component entity `id` has, including retained ones.
+    fn remove_all(&mut self, id: usize) {
+        let mut present = self
+            .bit_masks
+            .iter()
+            .filter(|(_, &mask)| self.map[id] & mask == mask)
+            .map(|(&type_id, &mask)| (type_id, mask))
+            .collect::<Vec<_>>();
+        present.sort_unstable_by_key(|&(_, mask)| mask);
+        for (type_id, mask) in present {
+            self.remove(id, &type_id, mask);
+        }
+    }
+
+    /// Stores `component` for entity `id`. Every component insertion goes through here so derived
+    /// indexes stay in sync with storage.
+    fn insert(&mut self, id: usize, type_id: TypeId, mask: M, component: Rc<RefCell<dyn Any>>) {
+        self.indexes
+            .get_mut()
+            .insert(id, &type_id, &*component.borrow());
+        self.components
+            .get_mut(&type_id)
+            .unwrap()
+            .insert(id, component);
+        if self.map[id] & mask != mask {
+            if self.map[id] == M::ZERO {
+                self.record(StructuralEvent::EntitySpawned(id));
+                self.spawned_at[id] = self.spawn_count;
+                self.spawn_count += 1;
+            }
+            self.record(StructuralEvent::ComponentAdded(id, type_id));
+            self.map[id] |= mask;
+            *self.populated.entry(type_id).or_default() += 1;
+            if let Some(gained) = self.gained.get_mut(&type_id) {
+                gained.push(id);
+            }
+            self.added.entry(type_id).or_default().insert(id);
+            self.structural_change();
+        } else {
+            self.changed
+                .get_mut()
+                .entry(type_id)
+                .or_default()
+                .insert(id);
+        }
+    }
+
+    /// Clears the component bit for entity `id`, the removal counterpart of `insert`. Returns the
+    /// removed value, if the entity had one.
+    fn remove(&mut self, id: usize, type_id: &TypeId, mask: M) -> Option<Rc<RefCell<dyn Any>>> {
+        if self.map[id] & mask != mask {
+            return None;
+        }
+        self.indexes.get_mut().remove(id, type_id);
+        let component = self.components.get_mut(type_id).unwrap().remove(id);
+        for tracked in [&mut self.added, self.changed.get_mut()] {
+            if let Some(ids) = tracked.get_mut(type_id) {
+                ids.remove(&id);
+            }
+        }
+        self.map[id] &= !mask;
+        *self.populated.get_mut(type_id).unwrap() -= 1;
+        self.record(StructuralEvent::ComponentRemoved(id, *type_id));
+        if self.map[id] == M::ZERO {
+            self.record(StructuralEvent::EntityDespawned(id));
+            self.free_slot(id);
+        }
+        self.structural_change();
+        component
+    }
+
+    fn structural_change(&mut self) {
+        if self.batching {
+            self.batch_changed = true;
+        } else {
+            self.structural_tick += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_errors::{CustomError, ErrorPolicy};
+    use crate::entities::mask::Mask;
+    use crate::entities::query::Query;
+    use crate::entities::schema::{ComponentSchema, FieldSchema};
+    use crate::entities::{Buffer, MergeStrategy, RemovalPolicy, StructuralEvent};
+    use eyre::Result;
+    use std::any::{type_name, TypeId};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    type Entities = crate::entities::Entities;
+
+    #[derive(Debug, PartialEq)]
+    struct Health(u32);
+
+    #[derive(Debug, PartialEq)]
+    struct Speed(u32);
+
+    #[test]
+    #[allow(clippy::unnecessary_get_then_check)]
+    fn register_entity() {
+        let mut entities = Entities::default();
+        assert!(entities.components.get(&TypeId::of::<Health>()).is_none());
+        entities.register_component::<Health>().unwrap();
+        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
+        assert_eq!(health_components.iter().count(), 0);
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_get_then_check)]
+    fn bitmask_updated_when_registering_entity() {
+        let mut entities = Entities::default();
+        assert!(entities.components.get(&TypeId::of::<Health>()).is_none());
+        entities.register_component::<Health>().unwrap();
+        entities.register_component::<Speed>().unwrap();
+        entities.register_component::<u32>().unwrap();
+
+        let bitmask = entities.bit_masks.get(&TypeId::of::<Health>()).unwrap();
+        assert_eq!(*bitmask, 1);
+
+        let bitmask = entities.bit_masks.get(&TypeId::of::<Speed>()).unwrap();
+        assert_eq!(*bitmask, 2);
+
+        let bitmask = entities.bit_masks.get(&TypeId::of::<u32>()).unwrap();
+        assert_eq!(*bitmask, 4);
+
+        // Does not exist
+        let bitmask = entities.bit_masks.get(&TypeId::of::<String>());
+        assert_eq!(bitmask, None);
+    }
+
+    #[test]
+    fn create_entity() {
+        let mut entities = Entities::default();
+        entities.register_component::<Health>().unwrap();
+        entities.register_component::<Speed>().unwrap();
+
+        entities.create_entity();
+        assert_eq!(entities.map.len(), 1);
+        assert_eq!(entities.generations.len(), 1);
+        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
+        let speed_components = entities.components.get(&TypeId::of::<Speed>()).unwrap();
+        assert!(health_components.get(0).is_none());
+        assert!(speed_components.get(0).is_none());
+    }
+
+    #[test]
+    fn with_component() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(10))?;
+
+        let health_component = entities
+            .components
+            .get(&TypeId::of::<Health>())
+            .unwrap()
+            .get(0)
+            .unwrap()
+            .borrow();
+        let health = health_component.downcast_ref::<Health>().unwrap();
+        assert_eq!(health, &Health(100));
+
+        let speed_component = entities
+            .components
+            .get(&TypeId::of::<Speed>())
+            .unwrap()
+            .get(0)
+            .unwrap()
+            .borrow();
+        let speed = speed_component.downcast_ref::<Speed>().unwrap();
+        assert_eq!(speed, &Speed(10));
+        Ok(())
+    }
+
+    #[test]
+    fn map_updated_when_creating_entities() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(10))?;
+        let entity_map = entities.map[0];
+        assert_eq!(entity_map, 3);
+
+        entities.create_entity().with_component(Speed(10))?;
+        let entity_map = entities.map[1];
+        assert_eq!(entity_map, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_component_by_entity_id() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(50))?;
+
+        entities.delete_component_by_entity_id::<Health>(0)?;
+
+        assert_eq!(entities.map[0], 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_absent_component_by_entity_id() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities.create_entity().with_component(Speed(50))?;
+
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        assert_eq!(entities.map[0], 2);
+        assert_eq!(entities.component_count::<Health>()?, 0);
+
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        assert_eq!(entities.map[0], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn add_component_by_entity_id() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities.create_entity().with_component(Health(100))?;
+
+        entities.add_component_by_entity_id(0, Speed(10))?;
+
+        assert_eq!(entities.map[0], 3);
+
+        let speed = entities
+            .components
+            .get(&TypeId::of::<Speed>())
+            .unwrap()
+            .get(0)
+            .unwrap()
+            .borrow();
+        let speed = speed.downcast_ref::<Speed>().unwrap();
+
+        assert_eq!(speed, &Speed(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn register_component_after_creating_entities() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(100))?;
+        entities.create_entity().with_component(Health(50))?;
+
+        entities.register_component::<Speed>()?;
+        entities.add_component_by_entity_id(1, Speed(10))?;
+        entities.create_entity().with_component(Speed(20))?;
+
+        assert_eq!(entities.map, vec![1, 3, 2]);
+        let speeds = entities
+            .column(&TypeId::of::<Speed>())
+            .map(|(id, speed)| (id, speed.borrow().downcast_ref::<Speed>().unwrap().0))
+            .collect::<Vec<_>>();
+        assert_eq!(speeds, vec![(1, 10), (2, 20)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_by_id() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        assert!(entities.delete_by_id(0).is_err());
+
+        entities.create_entity().with_component(Health(100))?;
+
+        entities.delete_by_id(0)?;
+
+        assert_eq!(entities.map[0], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        entities.create_entity().with_component(Health(100))?;
+
+        let held = entities.get_component::<Health>(0).unwrap();
+        entities.set_component(0, Health(50))?;
+        assert_eq!(held.borrow().downcast_ref::<Health>(), Some(&Health(50)));
+        assert!(Rc::ptr_eq(
+            &held,
+            &entities.get_component::<Health>(0).unwrap()
+        ));
+        assert!(entities.changed.borrow()[&TypeId::of::<Health>()].contains(&0));
+
+        entities.set_component(0, Speed(3))?;
+        assert_eq!(entities.map[0], 3);
+
+        let borrowed = held.borrow();
+        let error = entities.set_component(0, Health(10)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CustomError>(),
+            Some(CustomError::ComponentBorrowConflict { entity: 0, .. })
+        ));
+        drop(borrowed);
+
+        let error = entities.set_component(0, 1_u64).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::ComponentNotRegistered)
+        );
+        assert!(entities.set_component(1, Health(1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_entities() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_buffered_component::<Speed>()?;
+        for health in 0..4 {
+            entities.create_entity().with_component(Health(health))?;
+        }
+        entities.delete_by_id(1)?;
+        entities.delete_by_id(2)?;
+
+        let ids = entities.create_entities(5);
+        assert_eq!(ids, vec![1, 2, 4, 5, 6]);
+        assert_eq!(entities.map.len(), 7);
+        assert_eq!(entities.first_empty_index, 6);
+        assert_eq!(entities.previous[&TypeId::of::<Speed>()].len(), 7);
+
+        for &id in &ids {
+            entities.add_component_by_entity_id(id, Speed(id as u32))?;
+        }
+        assert_eq!(entities.entity_count(), 7);
+        assert_eq!(entities.create_entities(0), vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn entity_count() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        assert!(entities.is_empty());
+
+        entities.create_entity().with_component(Health(100))?;
+        entities
+            .create_entity()
+            .with_component(Health(50))?
+            .with_component(Speed(1))?;
+        entities.create_entity();
+        assert_eq!(entities.entity_count(), 2);
+        assert!(!entities.is_empty());
+
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        entities.delete_component_by_entity_id::<Health>(1)?;
+        assert_eq!(entities.entity_count(), 1);
+
+        entities.delete_by_id(1)?;
+        assert_eq!(entities.entity_count(), 0);
+        assert!(entities.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_entities() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        for health in 0..4 {
+            entities.create_entity().with_component(Health(health))?;
+        }
+        entities.delete_by_id(1)?;
+        entities.create_entity();
+
+        let live = entities.iter_entities().collect::<Vec<_>>();
+        let ids = live.iter().map(|entity| entity.index()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 2, 3]);
+        assert_eq!(live[1], entities.entity_at(2)?);
+        assert_eq!(entities.iter_entities().take(1).count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn generations() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        let first = entities
+            .create_entity()
+            .with_component(Health(100))?
+            .entity();
+        assert_eq!(entities.entity_at(0)?, first);
+        entities.add_component_by_entity_id(first, Speed(1))?;
+        entities.delete_by_id(first)?;
+
+        let stale = CustomError::EntityDoesNotExist;
+        let error = entities.delete_by_id(first).unwrap_err();
+        assert_eq!(error.downcast_ref::<CustomError>(), Some(&stale));
+
+        let second = entities
+            .create_entity()
+            .with_component(Health(50))?
+            .entity();
+        assert_eq!(second.index(), first.index());
+        assert_ne!(second.generation(), first.generation());
+        assert!(entities
+            .add_component_by_entity_id(first, Speed(2))
+            .is_err());
+        assert!(entities
+            .delete_component_by_entity_id::<Health>(first)
+            .is_err());
+        assert_eq!(entities.map[0], 1);
+
+        entities.add_tag(0, "marked")?;
+        entities.set_userdata(0, 7)?;
+        entities.delete_component_by_entity_id::<Health>(second)?;
+        assert!(!entities.has_tag(0, "marked"));
+        assert_eq!(entities.userdata(0), None);
+        let third = entities.create_entity().with_component(Speed(3))?.entity();
+        assert_eq!(third.index(), second.index());
+        assert_ne!(third, second);
+        let error = entities.delete_by_id(second).unwrap_err();
+        assert_eq!(error.downcast_ref::<CustomError>(), Some(&stale));
+        assert_eq!(entities.map[0], 2);
+        entities.delete_by_id(third)?;
+        assert_eq!(entities.entity_at(0)?.generation(), third.generation() + 1);
+        assert!(entities.entity_at(1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn created_entities_use_deleted_entities_space() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+
+        entities.create_entity().with_component(Health(100))?;
+        entities.create_entity().with_component(Health(50))?;
+
+        entities.delete_by_id(0)?;
+
+        entities.create_entity().with_component(Health(25))?;
+
+        assert_eq!(entities.map[0], 1);
+
+        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
+        let health = health_components.get(0).unwrap().borrow();
+        let health = health.downcast_ref::<Health>().unwrap();
+        assert_eq!(health, &Health(25));
+
+        Ok(())
+    }
+
+    #[test]
+    fn swap_buffers() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_buffered_component::<u32>()?;
+        entities.register_component::<Health>()?;
+
+        assert!(entities.swap_buffers::<String>().is_err());
+
+        entities.create_entity().with_component(10_u32)?;
+        entities.create_entity().with_component(Health(100))?;
+
+        let current = |entities: &Entities| {
+            let component = entities.get_buffered(&TypeId::of::<u32>(), 0, Buffer::Current);
+            *component.unwrap().borrow().downcast_ref::<u32>().unwrap()
+        };
+        let previous = |entities: &Entities| {
+            let component = entities.get_buffered(&TypeId::of::<u32>(), 0, Buffer::Previous);
+            *component.unwrap().borrow().downcast_ref::<u32>().unwrap()
+        };
+
+        assert_eq!(previous(&entities), 10);
+
+        entities.swap_buffers::<u32>()?;
+        entities.add_component_by_entity_id(0, 20_u32)?;
+
+        assert_eq!(current(&entities), 20);
+        assert_eq!(previous(&entities), 10);
+        assert!(entities.previous.get(&TypeId::of::<u32>()).unwrap()[1].is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn archetypes() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(10))?;
+        entities.create_entity().with_component(Health(50))?;
+        entities.create_entity().with_component(Health(25))?;
+        entities.create_entity().with_component(Speed(5))?;
+        entities.delete_by_id(3)?;
+
+        let archetypes = entities.archetypes();
+
+        assert_eq!(archetypes.len(), 2);
+        assert_eq!(archetypes[&3], 1);
+        assert_eq!(archetypes[&1], 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn entities_missing() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        assert!(entities.entities_missing::<Health, u32>().is_err());
+        assert!(entities.entities_missing::<u32, Health>().is_err());
+
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(10))?;
+        entities.create_entity().with_component(Health(50))?;
+        entities.create_entity().with_component(Speed(5))?;
+        entities.create_entity().with_component(Health(25))?;
+
+        assert_eq!(entities.entities_missing::<Health, Speed>()?, vec![1, 3]);
+        assert_eq!(entities.entities_missing::<Speed, Health>()?, vec![2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_policy_result() {
+        let mut entities = Entities::default();
+
+        assert!(entities.with_component(Health(100)).is_err());
+
+        entities.register_component::<Health>().unwrap();
+
+        assert!(entities.with_component(Health(100)).is_err());
+        assert!(entities.add_component_by_entity_id(3, Health(100)).is_err());
+        assert!(entities.delete_component_by_entity_id::<Health>(3).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "Attempted to reference an entity that doesn't exist")]
+    fn error_policy_panic() {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>().unwrap();
+        entities.set_error_policy(ErrorPolicy::Panic);
+
+        let _ = entities.add_component_by_entity_id(3, Health(100));
+    }
+
+    #[test]
+    fn spatial_index() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities.create_entity().with_component(Health(5))?;
+        entities.create_entity().with_component(Health(15))?;
+        entities.create_entity().with_component(Speed(5))?;
+
+        assert!(entities
+            .enable_spatial_index(10.0, |_: &u64| (0.0, 0.0))
+            .is_err());
+        assert!(entities
+            .enable_spatial_index(0.0, |health: &Health| (health.0 as f32, 0.0))
+            .is_err());
+        entities.enable_spatial_index(10.0, |health: &Health| (health.0 as f32, 0.0))?;
+
+        assert_eq!(entities.query_region((0.0, 0.0), (20.0, 0.0)), vec![0, 1]);
+
+        entities.create_entity().with_component(Health(8))?;
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        entities.add_component_by_entity_id(1, Health(30))?;
+
+        assert_eq!(entities.query_region((0.0, 0.0), (20.0, 0.0)), vec![3]);
+
+        entities.delete_by_id(3)?;
+        *entities.components[&TypeId::of::<Health>()]
+            .get(1)
+            .unwrap()
+            .borrow_mut()
+            .downcast_mut::<Health>()
+            .unwrap() = Health(12);
+
+        assert!(entities.query_region((0.0, 0.0), (20.0, 0.0)).is_empty());
+        entities.refresh_spatial_index();
+        assert_eq!(entities.query_region((0.0, 0.0), (20.0, 0.0)), vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn each_mut() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        assert!(entities.each_mut(|_, _: &mut u64| {}).is_err());
+
+        entities.create_entity().with_component(Health(10))?;
+        entities.create_entity().with_component(Speed(5))?;
+        entities.create_entity().with_component(Health(20))?;
+        entities.create_entity().with_component(Health(30))?;
+        entities.delete_by_id(3)?;
+
+        let mut visited = vec![];
+        entities.each_mut(|id, health: &mut Health| {
+            visited.push(id);
+            health.0 += 1;
+        })?;
+
+        assert_eq!(visited, vec![0, 2]);
+        let health = entities.components[&TypeId::of::<Health>()]
+            .get(2)
+            .unwrap()
+            .borrow();
+        assert_eq!(health.downcast_ref::<Health>().unwrap(), &Health(21));
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_bit_assignment() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        entities.register_component::<u32>()?;
+
+        entities.verify_bit_assignment()?;
+
+        entities.bit_masks.insert(TypeId::of::<Speed>(), 1);
+        entities.bit_masks.insert(TypeId::of::<u32>(), 6);
+
+        let error = entities.verify_bit_assignment().unwrap_err().to_string();
+        assert!(error.contains("Health, ecs_lib_rs::entities::tests::Speed share bit 0b1"));
+        assert!(error.contains("u32 have mask 0b110"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_component_or() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<u32>()?;
+        entities.register_component::<Health>()?;
+
+        entities.create_entity().with_component(10_u32)?;
+        entities.create_entity().with_component(Health(5))?;
+
+        assert_eq!(entities.get_component_or(0, 0_u32)?, 10);
+        assert_eq!(entities.get_component_or(1, 0_u32)?, 0);
+        assert!(entities.get_component_or(2, 0_u32).is_err());
+        assert!(entities.get_component_or(0, 0_u64).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn structural_tick() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities
+            .create_entity()
+            .with_component(Health(10))?
+            .with_component(Speed(1))?;
+        assert_eq!(entities.structural_tick(), 2);
+
+        entities.add_component_by_entity_id(0, Health(20))?;
+        assert_eq!(entities.structural_tick(), 2);
+
+        let was_batching = entities.begin_batch();
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        entities.create_entity().with_component(Speed(2))?;
+        assert_eq!(entities.structural_tick(), 2);
+        entities.end_batch(was_batching);
+        assert_eq!(entities.structural_tick(), 3);
+
+        let was_batching = entities.begin_batch();
+        entities.end_batch(was_batching);
+        assert_eq!(entities.structural_tick(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn track_relation() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<usize>()?;
+
+        entities.create_entity().with_component(Health(100))?;
+        entities.create_entity().with_component(0_usize)?;
+
+        assert!(entities
+            .track_relation(|owner: &u64| *owner as usize)
+            .is_err());
+        entities.track_relation(|owner: &usize| *owner)?;
+
+        entities.create_entity().with_component(0_usize)?;
+        entities.create_entity().with_component(1_usize)?;
+
+        assert_eq!(entities.related(0), &[1, 2]);
+        assert_eq!(entities.related(1), &[3]);
+
+        entities.each_mut(|_, owner: &mut usize| *owner = 1)?;
+
+        assert!(entities.related(0).is_empty());
+        assert_eq!(entities.related(1), &[1, 2, 3]);
+
+        entities.delete_component_by_entity_id::<usize>(1)?;
+        entities.delete_by_id(3)?;
+
+        assert_eq!(entities.related(1), &[2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_and_import_column() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<f32>()?;
+        entities.register_component::<Health>()?;
+
+        entities.create_entity().with_component(1.0_f32)?;
+        entities.create_entity().with_component(Health(10))?;
+        entities.create_entity().with_component(3.0_f32)?;
+
+        let (ids, values) = entities.export_column::<f32>(0..3)?;
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(values, vec![1.0, 3.0]);
+
+        assert!(entities.import_column(&[0, 1], &[2.0_f32]).is_err());
+        entities.import_column(&[0, 1], &[2.0_f32, 4.0])?;
+
+        let (ids, values) = entities.export_column::<f32>(0..3)?;
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(values, vec![2.0, 4.0, 3.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_with_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities
+            .create_entity()
+            .try_with_component(Health(100))?
+            .try_with_component(Speed(10))?;
+
+        assert!(entities.try_with_component(Health(50)).is_err());
+
+        let health = entities.components[&TypeId::of::<Health>()]
+            .get(0)
+            .unwrap()
+            .borrow();
+        assert_eq!(health.downcast_ref::<Health>().unwrap(), &Health(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn has_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(1))?;
+        entities.create_entity().with_component(Speed(2))?;
+
+        assert!(entities.has_component::<Health>(0));
+        assert!(!entities.has_component::<Health>(1));
+        assert!(entities.has_component::<Speed>(1));
+        assert!(!entities.has_component::<Speed>(2));
+        assert!(!entities.has_component::<u64>(0));
+
+        entities.delete_by_id(0)?;
+        assert!(!entities.has_component::<Speed>(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities.create_entity().with_component(Speed(1))?;
+        entities.create_entity().with_component(Health(100))?;
+
+        let health = entities.get_component::<Health>(1).unwrap();
+        assert_eq!(health.borrow().downcast_ref::<Health>(), Some(&Health(100)));
+        assert!(entities.get_component::<Health>(0).is_none());
+        assert!(entities.get_component::<Health>(2).is_none());
+        assert!(entities.get_component::<u64>(1).is_none());
+
+        entities.delete_component_by_entity_id::<Health>(1)?;
+        assert!(entities.get_component::<Health>(1).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_borrow_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+
+        entities.create_entity().with_component(Speed(1))?;
+        entities.create_entity().with_component(Health(100))?;
+
+        assert!(entities.try_borrow_component::<Health>(0)?.is_none());
+        assert!(entities.try_borrow_component::<Health>(2).is_err());
 
-    pub fn create_entity(&mut self) -> &mut Self {
-        if let Some((index, _)) = self.map.iter().enumerate().find(|(_, mask)| **mask == 0) {
-            self.first_empty_index = index;
-        } else {
-            self.components.iter_mut().for_each(|(_, v)| v.push(None));
-            self.map.push(0);
-            self.first_empty_index = self.map.len() - 1;
-        }
-        self
-    }
+        let health = entities.try_borrow_component_mut::<Health>(1)?.unwrap();
 
-    pub fn with_component(&mut self, component: impl Any) -> Result<&mut Self> {
-        let type_id = &component.type_id();
-        let index = self.first_empty_index;
-        match self.components.get_mut(type_id) {
-            None => Err(CustomError::ComponentNotRegistered.into()),
-            Some(component_list) => {
-                let component_at_index = component_list
-                    .get_mut(index)
-                    .ok_or(CustomError::CreateComponentNeverCalled)
-                    .unwrap();
-                *component_at_index = Some(Rc::new(RefCell::new(component)));
-                let bitmask = self.bit_masks.get(type_id).unwrap();
-                *(self.map.get_mut(index).unwrap()) |= bitmask;
-                Ok(self)
-            }
-        }
+        let conflict = CustomError::ComponentBorrowConflict {
+            entity: 1,
+            type_name: "ecs_lib_rs::entities::tests::Health",
+        };
+        let error = entities.try_borrow_component::<Health>(1).unwrap_err();
+        assert_eq!(error.downcast_ref::<CustomError>(), Some(&conflict));
+        let error = entities.try_borrow_component_mut::<Health>(1).unwrap_err();
+        assert_eq!(error.downcast_ref::<CustomError>(), Some(&conflict));
+
+        drop(health);
+
+        assert_eq!(
+            *entities.try_borrow_component::<Health>(1)?.unwrap(),
+            Health(100)
+        );
+
+        Ok(())
     }
 
-    pub fn get_bitmask(&self, type_id: &TypeId) -> Option<u32> {
-        self.bit_masks.get(type_id).copied()
+    #[test]
+    fn matching_archetype_of() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        entities.register_component::<u32>()?;
+
+        assert!(entities.matching_archetype_of(0).is_err());
+
+        entities
+            .create_entity()
+            .with_component(Health(100))?
+            .with_component(Speed(10))?;
+        entities.create_entity().with_component(Health(50))?;
+        entities
+            .create_entity()
+            .with_component(Health(25))?
+            .with_component(Speed(5))?
+            .with_component(1_u32)?;
+
+        assert_eq!(entities.matching_archetype_of(0)?, vec![0, 2]);
+        assert_eq!(entities.matching_archetype_of(1)?, vec![0, 1, 2]);
+
+        Ok(())
     }
 
-    pub fn delete_component_by_entity_id<T: Any>(&mut self, id: usize) -> Result<()> {
-        let type_id = TypeId::of::<T>();
-        match self.bit_masks.get(&type_id) {
-            None => Err(CustomError::ComponentNotRegistered.into()),
-            Some(&mask) => {
-                self.map[id] ^= mask;
-                Ok(())
-            }
-        }
+    #[test]
+    fn query_range() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<u32>()?;
+        entities.register_component::<Health>()?;
+
+        entities.create_entity().with_component(150_u32)?;
+        entities.create_entity().with_component(Health(150))?;
+
+        assert!(entities.query_range(100_u32, 200).is_err());
+        entities.index_component_sorted::<u32>()?;
+
+        entities.create_entity().with_component(120_u32)?;
+        entities.create_entity().with_component(300_u32)?;
+
+        assert_eq!(entities.query_range(100_u32, 200)?, vec![2, 0]);
+
+        entities.delete_by_id(2)?;
+        entities.add_component_by_entity_id(3, 180_u32)?;
+
+        assert_eq!(entities.query_range(100_u32, 200)?, vec![0, 3]);
+
+        Ok(())
     }
 
-    pub fn add_component_by_entity_id(&mut self, id: usize, component: impl Any) -> Result<()> {
-        let type_id = component.type_id();
-        match self.bit_masks.get(&type_id) {
-            None => Err(CustomError::ComponentNotRegistered.into()),
-            Some(&mask) => {
-                let components = self.components.get_mut(&type_id).unwrap();
-                components[id] = Some(Rc::new(RefCell::new(component)));
-                self.map[id] |= mask;
-                Ok(())
-            }
-        }
+    #[test]
+    fn owner_of_component() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.register_component_with_backref::<Health>()?;
+        entities.register_component_with_backref::<Speed>()?;
+
+        entities.create_entity().with_component(Speed(1))?;
+        entities.create_entity().with_component(Health(2))?;
+
+        let health = |id: usize| {
+            entities.components[&TypeId::of::<Health>()]
+                .get(id)
+                .cloned()
+        };
+        let (first, second) = (health(0).unwrap(), health(2).unwrap());
+
+        assert_eq!(entities.owner_of_component::<Health>(&first), Some(0));
+        assert_eq!(entities.owner_of_component::<Health>(&second), Some(2));
+        assert_eq!(entities.owner_of_component::<Speed>(&second), None);
+        assert_eq!(entities.owner_of_component::<u32>(&second), None);
+
+        entities.delete_component_by_entity_id::<Health>(2)?;
+
+        assert_eq!(entities.owner_of_component::<Health>(&second), None);
+
+        Ok(())
     }
 
-    pub fn delete_by_id(&mut self, id: usize) -> Result<()> {
-        match self.map.get_mut(id) {
-            None => Err(CustomError::EntityDoesNotExist.into()),
-            Some(entity) => {
-                *entity = 0;
-                Ok(())
-            }
-        }
+    #[test]
+    fn tags() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(Health(2))?;
+
+        entities.add_tag(0, "enemy")?;
+        entities.add_tag(0, "flying")?;
+        entities.add_tag(1, "enemy")?;
+
+        assert!(entities.has_tag(0, "flying"));
+        assert!(entities.has_tag(1, "enemy"));
+        assert!(!entities.has_tag(1, "flying"));
+        assert!(entities.add_tag(2, "enemy").is_err());
+
+        entities.remove_tag(0, "flying")?;
+        entities.remove_tag(1, "unknown")?;
+
+        assert!(!entities.has_tag(0, "flying"));
+        assert!(!entities.tags.contains_key("flying"));
+
+        entities.delete_by_id(1)?;
+
+        assert!(!entities.has_tag(1, "enemy"));
+        assert!(entities.has_tag(0, "enemy"));
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::entities::Entities;
-    use eyre::Result;
-    use std::any::TypeId;
+    #[test]
+    fn collect_copy() -> Result<()> {
+        let mut entities = Entities::default();
 
-    #[derive(Debug, PartialEq)]
-    struct Health(u32);
+        entities.register_component::<Health>()?;
+        entities.register_component::<u32>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(2_u32)?;
+        entities.create_entity().with_component(3_u32)?;
+        entities.create_entity().with_component(4_u32)?;
+        entities.delete_component_by_entity_id::<u32>(2)?;
 
-    #[derive(Debug, PartialEq)]
-    struct Speed(u32);
+        assert_eq!(entities.collect_copy::<u32>(), (vec![1, 3], vec![2, 4]));
+        assert_eq!(entities.collect_copy::<u64>(), (vec![], vec![]));
+
+        Ok(())
+    }
 
     #[test]
-    fn register_entity() {
+    fn checked_id() -> Result<()> {
         let mut entities = Entities::default();
-        assert!(entities.components.get(&TypeId::of::<Health>()).is_none());
-        entities.register_component::<Health>();
-        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
-        assert_eq!(health_components.len(), 0);
+
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(Health(2))?;
+
+        let first = entities.checked_id(0);
+        let second = entities.checked_id(1);
+        entities.create_entity().with_component(Health(3))?;
+        entities.delete_component_by_entity_id::<Health>(2)?;
+
+        assert_eq!(entities.resolve(first)?, 0);
+        assert!(entities.resolve(entities.checked_id(5)).is_err());
+
+        entities.delete_by_id(0)?;
+
+        let error = entities.resolve(second).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::StaleEntityId)
+        );
+        assert_eq!(entities.resolve(entities.checked_id(1))?, 1);
+
+        Ok(())
     }
 
+    #[derive(Debug, Clone, PartialEq)]
+    struct Name(&'static str);
+
     #[test]
-    fn bitmask_updated_when_registering_entity() {
+    fn undo_redo() -> Result<()> {
         let mut entities = Entities::default();
-        assert!(entities.components.get(&TypeId::of::<Health>()).is_none());
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
-        entities.register_component::<u32>();
 
-        let bitmask = entities.bit_masks.get(&TypeId::of::<Health>()).unwrap();
-        assert_eq!(*bitmask, 1);
+        entities.register_cloneable_component::<Name>()?;
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Name("a"))?;
 
-        let bitmask = entities.bit_masks.get(&TypeId::of::<Speed>()).unwrap();
-        assert_eq!(*bitmask, 2);
+        let name = |entities: &Entities, id: usize| entities.get_component_or(id, Name("-"));
 
-        let bitmask = entities.bit_masks.get(&TypeId::of::<u32>()).unwrap();
-        assert_eq!(*bitmask, 4);
+        entities.begin_edit()?;
+        entities.add_component_by_entity_id(0, Name("b"))?;
+        entities.commit_edit();
 
-        // Does not exist
-        let bitmask = entities.bit_masks.get(&TypeId::of::<String>());
-        assert_eq!(bitmask, None);
+        entities.begin_edit()?;
+        entities.create_entity().with_component(Name("c"))?;
+        entities.commit_edit();
+
+        assert!(entities.undo()?);
+        assert_eq!(entities.map, vec![1, 0]);
+        assert_eq!(name(&entities, 0)?, Name("b"));
+
+        assert!(entities.undo()?);
+        assert_eq!(name(&entities, 0)?, Name("a"));
+        assert!(!entities.undo()?);
+
+        assert!(entities.redo()?);
+        assert_eq!(name(&entities, 0)?, Name("b"));
+        assert_eq!(name(&entities, 1)?, Name("-"));
+
+        entities.begin_edit()?;
+        entities.add_tag(0, "edited")?;
+        entities.commit_edit();
+        assert!(!entities.redo()?);
+
+        entities.add_component_by_entity_id(0, Health(1))?;
+        assert!(entities.begin_edit().is_err());
+
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        entities.set_undo_depth(1);
+        assert!(entities.undo()?);
+        assert!(!entities.has_tag(0, "edited"));
+        assert!(!entities.undo()?);
+
+        Ok(())
     }
 
     #[test]
-    fn create_entity() {
+    fn schema() -> Result<()> {
         let mut entities = Entities::default();
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
 
-        entities.create_entity();
-        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
-        let speed_components = entities.components.get(&TypeId::of::<Speed>()).unwrap();
-        assert_eq!(health_components.len(), 1);
-        assert_eq!(speed_components.len(), 1);
-        assert!(health_components[0].is_none());
-        assert!(speed_components[0].is_none());
+        entities.register_component::<Speed>()?;
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(Health(2))?;
+
+        assert!(entities
+            .register_reflection::<u32>(|| vec![FieldSchema::new("0", "u32")])
+            .is_err());
+        entities.register_reflection::<Health>(|| vec![FieldSchema::new("0", "u32")])?;
+
+        assert_eq!(
+            entities.schema(),
+            vec![
+                ComponentSchema {
+                    type_name: type_name::<Speed>(),
+                    bit_index: 0,
+                    entity_count: 0,
+                    fields: vec![],
+                },
+                ComponentSchema {
+                    type_name: type_name::<Health>(),
+                    bit_index: 1,
+                    entity_count: 2,
+                    fields: vec![FieldSchema::new("0", "u32")],
+                },
+            ]
+        );
+
+        Ok(())
     }
 
     #[test]
-    fn with_component() -> Result<()> {
+    fn recompute_masks() -> Result<()> {
         let mut entities = Entities::default();
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
         entities
             .create_entity()
-            .with_component(Health(100))?
-            .with_component(Speed(10))?;
+            .with_component(Health(1))?
+            .with_component(Speed(1))?;
+        entities.create_entity().with_component(Health(2))?;
+        entities.create_entity().with_component(Speed(3))?;
 
-        let health_component = entities
+        entities
             .components
-            .get(&TypeId::of::<Health>())
-            .unwrap()
-            .first()
-            .unwrap()
-            .as_ref()
+            .get_mut(&TypeId::of::<Health>())
             .unwrap()
-            .borrow();
-        let health = health_component.downcast_ref::<Health>().unwrap();
-        assert_eq!(health, &Health(100));
+            .remove(0);
+        let speed = Rc::new(RefCell::new(Speed(2)));
+        let speeds = entities.components.get_mut(&TypeId::of::<Speed>()).unwrap();
+        speeds.insert(1, speed);
+        speeds.remove(2);
+        let tick = entities.structural_tick();
+        entities.record_structural_events();
+
+        entities.recompute_masks();
+
+        assert_eq!(entities.map, vec![2, 3, 0]);
+        assert_eq!(entities.structural_tick(), tick + 1);
+        let matched = Query::new(&entities).with_component::<Health>()?.run().ids;
+        assert_eq!(matched, vec![1]);
+        assert!(!entities.changed_or_added(0, &TypeId::of::<Health>()));
+        assert_eq!(entities.generations, vec![0, 0, 1]);
+        let events = entities.drain_structural_events();
+        assert!(events.contains(&StructuralEvent::ComponentRemoved(
+            0,
+            TypeId::of::<Health>()
+        )));
+        assert!(events.contains(&StructuralEvent::EntityDespawned(2)));
+
+        entities.recompute_masks();
+        assert_eq!(entities.structural_tick(), tick + 1);
 
-        let speed_component = entities
-            .components
-            .get(&TypeId::of::<Speed>())
-            .unwrap()
-            .first()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .borrow();
-        let speed = speed_component.downcast_ref::<Speed>().unwrap();
-        assert_eq!(speed, &Speed(10));
         Ok(())
     }
 
     #[test]
-    fn map_updated_when_creating_entities() -> Result<()> {
+    fn despawn_log() -> Result<()> {
         let mut entities = Entities::default();
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
-        entities
-            .create_entity()
-            .with_component(Health(100))?
-            .with_component(Speed(10))?;
-        let entity_map = entities.map[0];
-        assert_eq!(entity_map, 3);
 
-        entities.create_entity().with_component(Speed(10))?;
-        let entity_map = entities.map[1];
-        assert_eq!(entity_map, 2);
+        entities.register_component::<Health>()?;
+        for health in 0..4 {
+            entities.create_entity().with_component(Health(health))?;
+        }
+
+        entities.despawn_with_reason(0, "ignored")?;
+        assert!(entities.despawn_log().is_empty());
+        assert!(entities.despawn_with_reason(9, "missing").is_err());
+
+        entities.set_despawn_log_cap(2);
+        entities.despawn_with_reason(1, "killed")?;
+        entities.despawn_with_reason(2, "fell")?;
+        entities.despawn_with_reason(3, "expired")?;
+
+        assert_eq!(entities.map, vec![0, 0, 0, 0]);
+        assert_eq!(
+            entities.despawn_log(),
+            &[(2, "fell".to_owned()), (3, "expired".to_owned())]
+        );
+
+        entities.set_despawn_log_cap(1);
+        assert_eq!(entities.despawn_log(), &[(3, "expired".to_owned())]);
+
         Ok(())
     }
 
     #[test]
-    fn delete_component_by_entity_id() -> Result<()> {
+    fn trackers() -> Result<()> {
         let mut entities = Entities::default();
 
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
+        entities.register_component::<Health>()?;
+        entities.register_component::<u32>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(Health(2))?;
+        entities.create_entity().with_component(3_u32)?;
+
+        let health = TypeId::of::<Health>();
+        assert!(entities.changed_or_added(0, &health));
+        assert!(!entities.changed_or_added(2, &health));
+
+        entities.clear_trackers();
+        assert!(!entities.changed_or_added(0, &health));
+
+        entities.add_component_by_entity_id(1, Health(20))?;
+        entities.import_column(&[2], &[30_u32])?;
+        assert!(entities.changed_or_added(1, &health));
+        assert!(entities.changed_or_added(2, &TypeId::of::<u32>()));
 
+        entities.delete_component_by_entity_id::<Health>(1)?;
+        assert!(!entities.changed_or_added(1, &health));
+
+        entities.each_mut(|_, health: &mut Health| health.0 += 1)?;
+        assert!(entities.changed_or_added(0, &health));
+
+        Ok(())
+    }
+
+    fn mask_width<M: Mask>() -> Result<()> {
+        let mut entities = crate::entities::Entities::<M>::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
         entities
             .create_entity()
-            .with_component(Health(100))?
-            .with_component(Speed(50))?;
+            .with_component(Health(1))?
+            .with_component(Speed(1))?;
+        entities.create_entity().with_component(Speed(2))?;
 
-        entities.delete_component_by_entity_id::<Health>(0)?;
+        let (health, speed) = (entities.mask_of::<Health>()?, entities.mask_of::<Speed>()?);
+        assert_eq!((health, speed), (M::bit(0), M::bit(1)));
+        assert_eq!(entities.map, vec![health | speed, speed]);
+        assert_eq!(entities.archetypes().len(), 2);
 
-        assert_eq!(entities.map[0], 2);
+        let mut query = Query::new(&entities);
+        assert_eq!(
+            query.require_mask(speed).exclude_mask(health).run().ids,
+            vec![1]
+        );
 
         Ok(())
     }
 
     #[test]
-    fn add_component_by_entity_id() -> Result<()> {
-        let mut entities = Entities::default();
+    fn mask_widths() -> Result<()> {
+        mask_width::<u32>()?;
+        mask_width::<u128>()
+    }
 
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
+    #[test]
+    fn merge() -> Result<()> {
+        let source = || -> Result<Entities> {
+            let mut source = Entities::default();
+            source.register_component::<Speed>()?;
+            source.create_entity().with_component(Speed(10))?;
+            source.create_entity().with_component(Speed(11))?;
+            source.create_entity().with_component(Speed(12))?;
+            source.delete_by_id(1)?;
+            source.add_tag(2, "fast")?;
+            Ok(source)
+        };
+        let destination = || -> Result<Entities> {
+            let mut destination = Entities::default();
+            destination.register_component::<Health>()?;
+            destination.register_component::<Speed>()?;
+            destination.create_entity().with_component(Health(1))?;
+            destination.create_entity().with_component(Health(2))?;
+            destination.delete_by_id(0)?;
+            Ok(destination)
+        };
 
-        entities.create_entity().with_component(Health(100))?;
+        let mut entities = destination()?;
+        let placed = entities.merge(source()?, MergeStrategy::Remap)?;
+        assert_eq!(placed, HashMap::from([(0, 0), (2, 2)]));
+        assert_eq!(entities.map, vec![2, 1, 2]);
+        assert!(entities.has_tag(2, "fast"));
 
-        entities.add_component_by_entity_id(0, Speed(10))?;
+        let mut entities = destination()?;
+        let placed = entities.merge(source()?, MergeStrategy::Overwrite)?;
+        assert_eq!(placed, HashMap::from([(0, 0), (2, 2)]));
+        assert_eq!(
+            entities.try_borrow_component::<Speed>(2)?.as_deref(),
+            Some(&Speed(12))
+        );
+        assert_eq!(entities.map, vec![2, 1, 2]);
 
-        assert_eq!(entities.map[0], 3);
+        let mut entities = destination()?;
+        entities.create_entity().with_component(Health(3))?;
+        entities.create_entity().with_component(Health(4))?;
+        entities.delete_by_id(0)?;
+        let placed = entities.merge(source()?, MergeStrategy::Skip)?;
+        assert_eq!(placed, HashMap::from([(0, 0)]));
+        assert_eq!(entities.map, vec![2, 1, 1]);
+        assert!(!entities.has_tag(2, "fast"));
 
-        let speed = entities.components.get(&TypeId::of::<Speed>()).unwrap()[0]
-            .as_ref()
-            .unwrap()
-            .borrow();
-        let speed = speed.downcast_ref::<Speed>().unwrap();
+        let mut unregistered = Entities::default();
+        unregistered.register_component::<u32>()?;
+        unregistered.create_entity().with_component(1_u32)?;
+        assert!(entities.merge(unregistered, MergeStrategy::Remap).is_err());
+        assert_eq!(entities.map, vec![2, 1, 1]);
 
-        assert_eq!(speed, &Speed(10));
+        Ok(())
+    }
+
+    #[test]
+    fn gained() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        assert!(entities.observe_added::<u32>().is_err());
+
+        entities.create_entity().with_component(Health(1))?;
+        entities.observe_added::<Health>()?;
+        entities.create_entity().with_component(Speed(1))?;
+        entities.add_component_by_entity_id(1, Health(2))?;
+        entities.create_entity().with_component(Health(3))?;
+        entities.add_component_by_entity_id(0, Health(4))?;
+        entities.delete_component_by_entity_id::<Health>(1)?;
+        entities.add_component_by_entity_id(1, Health(5))?;
+
+        let health = TypeId::of::<Health>();
+        assert_eq!(entities.take_gained(&health), vec![1, 2]);
+        assert!(entities.take_gained(&health).is_empty());
+        assert!(entities.take_gained(&TypeId::of::<Speed>()).is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn delete_by_id() -> Result<()> {
+    fn userdata() -> Result<()> {
         let mut entities = Entities::default();
 
-        entities.register_component::<Health>();
-        entities.register_component::<Speed>();
+        entities.register_component::<Health>()?;
+        entities.create_entity().with_component(Health(1))?;
+        entities.create_entity().with_component(Health(2))?;
 
-        assert!(entities.delete_by_id(0).is_err());
+        entities.set_userdata(0, 0xdead_beef)?;
+        entities.set_userdata(1, 7)?;
+        entities.set_userdata(1, 8)?;
+        assert!(entities.set_userdata(2, 1).is_err());
 
-        entities.create_entity().with_component(Health(100))?;
+        assert_eq!(entities.userdata(0), Some(0xdead_beef));
+        assert_eq!(entities.userdata(1), Some(8));
+        assert_eq!(entities.userdata(2), None);
 
         entities.delete_by_id(0)?;
+        assert_eq!(entities.userdata(0), None);
+        assert_eq!(entities.userdata(1), Some(8));
 
-        assert_eq!(entities.map[0], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn each_mut_try() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        for health in 0..4 {
+            entities.create_entity().with_component(Health(health))?;
+        }
+        entities.create_entity().with_component(Speed(1))?;
+
+        let held = entities.try_borrow_component_mut::<Health>(2)?.unwrap();
+        let mut visited = vec![];
+        let skipped = entities.each_mut_try(|id, health: &mut Health| {
+            visited.push(id);
+            health.0 += 10;
+        })?;
+        drop(held);
+
+        assert_eq!(visited, vec![0, 1, 3]);
+        assert_eq!(skipped, vec![2]);
+        assert_eq!(
+            *entities.try_borrow_component::<Health>(2)?.unwrap(),
+            Health(2)
+        );
+        assert_eq!(
+            *entities.try_borrow_component::<Health>(3)?.unwrap(),
+            Health(13)
+        );
+        assert!(entities.each_mut_try(|_, _: &mut u8| {}).is_err());
 
         Ok(())
     }
 
     #[test]
-    fn created_entities_use_deleted_entities_space() -> Result<()> {
+    fn component_count() -> Result<()> {
         let mut entities = Entities::default();
 
-        entities.register_component::<Health>();
+        entities.register_component::<Health>()?;
+        entities.register_component::<Speed>()?;
+        assert_eq!(entities.component_count::<Health>()?, 0);
 
-        entities.create_entity().with_component(Health(100))?;
-        entities.create_entity().with_component(Health(50))?;
+        for health in 0..3 {
+            entities.create_entity().with_component(Health(health))?;
+        }
+        entities.add_component_by_entity_id(1, Speed(1))?;
+        entities.add_component_by_entity_id(1, Health(7))?;
+        assert_eq!(entities.component_count::<Health>()?, 3);
+        assert_eq!(entities.component_count::<Speed>()?, 1);
 
-        entities.delete_by_id(0)?;
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        entities.delete_by_id(1)?;
+        assert_eq!(entities.component_count::<Health>()?, 1);
+        assert_eq!(entities.component_count::<Speed>()?, 0);
+        assert!(entities.component_count::<u8>().is_err());
 
-        entities.create_entity().with_component(Health(25))?;
+        Ok(())
+    }
 
-        assert_eq!(entities.map[0], 1);
+    #[test]
+    fn register_component_named() -> Result<()> {
+        let mut entities = Entities::default();
 
-        let health_components = entities.components.get(&TypeId::of::<Health>()).unwrap();
-        let health = health_components[0].as_ref().unwrap().borrow();
-        let health = health.downcast_ref::<Health>().unwrap();
-        assert_eq!(health, &Health(25));
+        entities.register_component_named::<Health>("health")?;
+        entities.register_component_named::<Speed>("speed")?;
+        entities.create_entity().with_component(Speed(3))?;
+
+        assert_eq!(
+            entities.component_by_tag("health"),
+            Some(TypeId::of::<Health>())
+        );
+        assert_eq!(
+            entities.component_by_tag("speed"),
+            Some(TypeId::of::<Speed>())
+        );
+        assert_eq!(entities.component_tag::<Speed>(), Some("speed"));
+        assert_eq!(entities.component_by_tag("missing"), None);
+
+        let duplicate = entities.register_component_named::<u8>("health");
+        assert_eq!(
+            duplicate.unwrap_err().downcast::<CustomError>()?,
+            CustomError::DuplicateComponentTag("health")
+        );
+        assert_eq!(entities.get_bitmask(&TypeId::of::<u8>()), None);
+
+        entities.register_component_named::<Speed>("velocity")?;
+        assert_eq!(entities.component_by_tag("speed"), None);
+        assert_eq!(
+            entities.component_by_tag("velocity"),
+            Some(TypeId::of::<Speed>())
+        );
+        assert_eq!(entities.map, vec![0b10]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn removal_policy() -> Result<()> {
+        let mut entities = Entities::default();
+
+        entities.register_component::<Health>()?;
+        entities.register_component_removal::<Speed>(RemovalPolicy::Retain)?;
+        entities
+            .create_entity()
+            .with_component(Health(1))?
+            .with_component(Speed(1))?;
+        let health = Rc::downgrade(entities.stored(0, &TypeId::of::<Health>()).unwrap());
+        let speed = Rc::downgrade(entities.stored(0, &TypeId::of::<Speed>()).unwrap());
+
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        assert!(speed.upgrade().is_some());
+        assert_eq!(entities.map, vec![0b01]);
+        assert_eq!(entities.component_count::<Speed>()?, 0);
+
+        entities.recompute_masks();
+        assert_eq!(entities.map, vec![0b01]);
+
+        assert!(entities.reinstate_component::<Speed>(0)?);
+        assert_eq!(entities.map, vec![0b11]);
+        assert!(Rc::ptr_eq(
+            &speed.upgrade().unwrap(),
+            entities.stored(0, &TypeId::of::<Speed>()).unwrap()
+        ));
+
+        entities.delete_component_by_entity_id::<Health>(0)?;
+        assert!(health.upgrade().is_none());
+        assert!(!entities.reinstate_component::<Health>(0)?);
+
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        assert!(speed.upgrade().is_none());
+        entities.create_entity();
+        assert!(!entities.reinstate_component::<Speed>(0)?);
+
+        entities.add_component_by_entity_id(0, Health(2))?;
+        entities.add_component_by_entity_id(0, Speed(2))?;
+        let speed = Rc::downgrade(entities.stored(0, &TypeId::of::<Speed>()).unwrap());
+        entities.delete_component_by_entity_id::<Speed>(0)?;
+        entities.delete_by_id(0)?;
+        assert!(speed.upgrade().is_none());
+        assert!(!entities.reinstate_component::<Speed>(0)?);
 
         Ok(())
     }
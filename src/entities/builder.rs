@@ -0,0 +1,68 @@
+use crate::entities::mask::Mask;
+use crate::entities::Entities;
+use eyre::Result;
+use std::any::Any;
+
+type AddComponent<M> = Box<dyn FnOnce(&mut Entities<M>, usize) -> Result<()>>;
+
+/// Collects the components of an entity to be spawned, such as the components a prototype
+/// describes.
+pub struct EntityBuilder<M: Mask = u64> {
+    components: Vec<AddComponent<M>>,
+}
+
+impl<M: Mask> Default for EntityBuilder<M> {
+    fn default() -> Self {
+        Self { components: vec![] }
+    }
+}
+
+impl<M: Mask> EntityBuilder<M> {
+    pub fn with_component(&mut self, component: impl Any) -> &mut Self {
+        self.components.push(Box::new(move |entities, id| {
+            entities.add_component_by_entity_id(id, component)
+        }));
+        self
+    }
+
+    /// Creates an entity with the collected components. If one of them can't be added, the
+    /// partially built entity is deleted again.
+    pub(crate) fn spawn(self, entities: &mut Entities<M>) -> Result<usize> {
+        let id = entities.create_entity().first_empty_index;
+        for add in self.components {
+            if let Err(error) = add(entities, id) {
+                entities.delete_by_id(id)?;
+                return Err(error);
+            }
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::builder::EntityBuilder;
+    use eyre::Result;
+
+    type Entities = crate::entities::Entities;
+
+    #[test]
+    fn spawn() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.create_entity().with_component(0_u32)?;
+
+        let mut builder = EntityBuilder::default();
+        builder.with_component(1_u32).with_component(1.0_f32);
+        assert_eq!(builder.spawn(&mut entities)?, 1);
+        assert_eq!(entities.map, vec![1, 3]);
+
+        let mut builder = EntityBuilder::default();
+        builder.with_component(2_u32).with_component(2_u64);
+        assert!(builder.spawn(&mut entities).is_err());
+        assert_eq!(entities.map, vec![1, 3, 0]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use crate::entities::mask::Mask;
+use crate::entities::Entities;
+use eyre::Result;
+use std::any::{Any, TypeId};
+
+/// A tuple of up to twelve components that `Entities::with_bundle` adds to an entity in one call,
+/// left to right.
+pub trait Bundle {
+    fn type_ids() -> Vec<TypeId>;
+
+    fn add_to<M: Mask>(self, entities: &mut Entities<M>) -> Result<()>;
+}
+
+macro_rules! impl_bundle {
+    ($($component:ident),*) => {
+        impl<$($component: Any),*> Bundle for ($($component,)*) {
+            fn type_ids() -> Vec<TypeId> {
+                vec![$(TypeId::of::<$component>()),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn add_to<M: Mask>(self, entities: &mut Entities<M>) -> Result<()> {
+                let ($($component,)*) = self;
+                $(entities.with_component($component)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_bundle!(A);
+impl_bundle!(A, B);
+impl_bundle!(A, B, C);
+impl_bundle!(A, B, C, D);
+impl_bundle!(A, B, C, D, E);
+impl_bundle!(A, B, C, D, E, F);
+impl_bundle!(A, B, C, D, E, F, G);
+impl_bundle!(A, B, C, D, E, F, G, H);
+impl_bundle!(A, B, C, D, E, F, G, H, I);
+impl_bundle!(A, B, C, D, E, F, G, H, I, J);
+impl_bundle!(A, B, C, D, E, F, G, H, I, J, K);
+impl_bundle!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_errors::CustomError;
+    use eyre::Result;
+
+    type Entities = crate::entities::Entities;
+
+    #[test]
+    fn with_bundle() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        entities
+            .create_entity()
+            .with_bundle((1_u32, 1.0_f32, -1_i8))?;
+        entities
+            .create_entity()
+            .with_bundle((2_u32,))?
+            .with_component(2.0_f32)?;
+        assert_eq!(entities.map, vec![7, 3]);
+
+        let error = entities
+            .create_entity()
+            .with_bundle((3_u32, 3_u64))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::ComponentNotRegistered)
+        );
+        assert_eq!(entities.map, vec![7, 3, 0]);
+
+        Ok(())
+    }
+}
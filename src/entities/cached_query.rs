@@ -0,0 +1,65 @@
+use crate::entities::mask::Mask;
+use crate::entities::query::Query;
+use crate::entities::Buffer;
+use crate::WorldGeneric;
+use eyre::Result;
+use std::any::{Any, TypeId};
+
+/// The components a query requires, described without borrowing a world so it can be stored.
+#[derive(Default, Debug, Clone)]
+pub struct QueryDescriptor {
+    type_ids: Vec<TypeId>,
+}
+
+impl QueryDescriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_component<T: Any>(mut self) -> Self {
+        self.type_ids.push(TypeId::of::<T>());
+        self
+    }
+
+    /// Builds the described query against `world`, failing if a component isn't registered.
+    pub fn query<'a, M: Mask>(&self, world: &'a WorldGeneric<M>) -> Result<Query<'a, M>> {
+        let mut query = world.query();
+        for &type_id in &self.type_ids {
+            query.with_type_id(type_id, Buffer::Current)?;
+        }
+        Ok(query)
+    }
+}
+
+/// Remembers the entities matched by a `QueryDescriptor` and only rescans when the structural
+/// tick shows that a component was added or removed since. Use one cache per world.
+#[derive(Debug)]
+pub struct CachedQuery {
+    descriptor: QueryDescriptor,
+    indices: Vec<usize>,
+    computed_at: Option<u64>,
+}
+
+impl CachedQuery {
+    pub fn new(descriptor: QueryDescriptor) -> Self {
+        Self {
+            descriptor,
+            indices: vec![],
+            computed_at: None,
+        }
+    }
+
+    pub fn get<M: Mask>(&mut self, world: &WorldGeneric<M>) -> Result<&[usize]> {
+        let tick = world.structural_tick();
+        if self.computed_at != Some(tick) {
+            self.indices = self.descriptor.query(world)?.iter_entities().collect();
+            self.computed_at = Some(tick);
+        }
+        Ok(&self.indices)
+    }
+
+    /// The structural tick the cached entities were computed at, if they were computed yet.
+    pub fn computed_at(&self) -> Option<u64> {
+        self.computed_at
+    }
+}
@@ -0,0 +1,99 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+type Extract = Box<dyn Fn(&dyn Any) -> (f32, f32)>;
+
+/// The bounding box of every position that changed since the last reset, as
+/// `(min_x, min_y, max_x, max_y)`.
+pub type Region = (f32, f32, f32, f32);
+
+/// Grows a bounding box over the old and new positions of entities whose position component,
+/// read out by `extract`, is added, replaced or removed.
+pub struct DirtyRegion {
+    pub(crate) type_id: TypeId,
+    extract: Extract,
+    positions: HashMap<usize, (f32, f32)>,
+    region: Option<Region>,
+}
+
+impl DirtyRegion {
+    pub fn new<Pos: Any>(extract: impl Fn(&Pos) -> (f32, f32) + 'static) -> Self {
+        Self {
+            type_id: TypeId::of::<Pos>(),
+            extract: Box::new(move |component| extract(component.downcast_ref::<Pos>().unwrap())),
+            positions: HashMap::new(),
+            region: None,
+        }
+    }
+
+    /// Records where entity `id` is without marking anything dirty.
+    pub fn track(&mut self, id: usize, component: &dyn Any) {
+        self.positions.insert(id, (self.extract)(component));
+    }
+
+    pub fn insert(&mut self, id: usize, component: &dyn Any) {
+        self.remove(id);
+        let position = (self.extract)(component);
+        self.expand(position);
+        self.positions.insert(id, position);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(position) = self.positions.remove(&id) {
+            self.expand(position);
+        }
+    }
+
+    fn expand(&mut self, (x, y): (f32, f32)) {
+        self.region = Some(match self.region {
+            None => (x, y, x, y),
+            Some((min_x, min_y, max_x, max_y)) => {
+                (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+            }
+        });
+    }
+
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    pub fn reset(&mut self) {
+        self.region = None;
+    }
+}
+
+impl fmt::Debug for DirtyRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirtyRegion")
+            .field("type_id", &self.type_id)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::dirty::DirtyRegion;
+
+    struct Position(f32, f32);
+
+    #[test]
+    fn region() {
+        let mut dirty = DirtyRegion::new(|p: &Position| (p.0, p.1));
+
+        dirty.track(0, &Position(-50.0, -50.0));
+        assert_eq!(dirty.region(), None);
+
+        dirty.insert(1, &Position(2.0, 3.0));
+        assert_eq!(dirty.region(), Some((2.0, 3.0, 2.0, 3.0)));
+
+        dirty.insert(1, &Position(5.0, 1.0));
+        assert_eq!(dirty.region(), Some((2.0, 1.0, 5.0, 3.0)));
+
+        dirty.reset();
+        dirty.remove(0);
+        dirty.remove(7);
+        assert_eq!(dirty.region(), Some((-50.0, -50.0, -50.0, -50.0)));
+    }
+}
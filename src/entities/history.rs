@@ -0,0 +1,53 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// How many committed edits are kept for undo unless configured otherwise.
+pub const DEFAULT_UNDO_DEPTH: usize = 64;
+
+/// Clones a type-erased component value into a fresh cell.
+pub type Cloner = fn(&dyn Any) -> Rc<RefCell<dyn Any>>;
+
+type Component = (usize, TypeId, Rc<RefCell<dyn Any>>);
+
+pub fn cloner<T: Any + Clone>(component: &dyn Any) -> Rc<RefCell<dyn Any>> {
+    Rc::new(RefCell::new(component.downcast_ref::<T>().unwrap().clone()))
+}
+
+/// A deep copy of every entity's components and tags at one point in time.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub components: Vec<Component>,
+    pub tags: HashMap<String, HashSet<usize>>,
+}
+
+/// Undo and redo stacks of snapshots taken around editor edits.
+#[derive(Debug)]
+pub struct History {
+    pub open: Option<Snapshot>,
+    pub undo: VecDeque<Snapshot>,
+    pub redo: Vec<Snapshot>,
+    pub depth: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            open: None,
+            undo: VecDeque::new(),
+            redo: vec![],
+            depth: DEFAULT_UNDO_DEPTH,
+        }
+    }
+}
+
+impl History {
+    /// Pushes the state from before an edit, dropping the oldest entries beyond the depth.
+    pub fn push_undo(&mut self, snapshot: Snapshot) {
+        self.undo.push_back(snapshot);
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::entities::dirty::DirtyRegion;
+use crate::entities::relation::Relation;
+use crate::entities::sorted::ValueIndex;
+use crate::entities::spatial::SpatialIndex;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// Lookup structures derived from component values, kept in sync with component storage.
+#[derive(Default, Debug)]
+pub struct Indexes {
+    pub spatial: Option<SpatialIndex>,
+    pub relation: Option<Relation>,
+    pub sorted: HashMap<TypeId, Box<dyn ValueIndex>>,
+    pub backrefs: HashMap<TypeId, Backrefs>,
+    pub dirty: Option<DirtyRegion>,
+}
+
+/// Maps the address of each stored component value back to the entity that owns it.
+#[derive(Default, Debug)]
+pub struct Backrefs {
+    owners: HashMap<usize, usize>,
+    addresses: HashMap<usize, usize>,
+}
+
+impl Backrefs {
+    pub fn insert(&mut self, id: usize, component: &dyn Any) {
+        self.remove(id);
+        let address = component as *const dyn Any as *const () as usize;
+        self.owners.insert(address, id);
+        self.addresses.insert(id, address);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(address) = self.addresses.remove(&id) {
+            self.owners.remove(&address);
+        }
+    }
+
+    pub fn owner(&self, component: *const dyn Any) -> Option<usize> {
+        self.owners.get(&(component as *const () as usize)).copied()
+    }
+}
+
+impl Indexes {
+    /// Whether any index is derived from `type_id` components, so writes to them need `insert`.
+    pub fn covers(&self, type_id: &TypeId) -> bool {
+        self.spatial.as_ref().is_some_and(|s| s.type_id == *type_id)
+            || self
+                .relation
+                .as_ref()
+                .is_some_and(|r| r.type_id == *type_id)
+            || self.sorted.contains_key(type_id)
+            || self.backrefs.contains_key(type_id)
+            || self.dirty.as_ref().is_some_and(|d| d.type_id == *type_id)
+    }
+
+    pub fn insert(&mut self, id: usize, type_id: &TypeId, component: &dyn Any) {
+        if let Some(spatial) = self.spatial.as_mut().filter(|s| s.type_id == *type_id) {
+            spatial.insert(id, component);
+        }
+        if let Some(relation) = self.relation.as_mut().filter(|r| r.type_id == *type_id) {
+            relation.insert(id, component);
+        }
+        if let Some(sorted) = self.sorted.get_mut(type_id) {
+            sorted.insert(id, component);
+        }
+        if let Some(backrefs) = self.backrefs.get_mut(type_id) {
+            backrefs.insert(id, component);
+        }
+        if let Some(dirty) = self.dirty.as_mut().filter(|d| d.type_id == *type_id) {
+            dirty.insert(id, component);
+        }
+    }
+
+    pub fn remove(&mut self, id: usize, type_id: &TypeId) {
+        if let Some(spatial) = self.spatial.as_mut().filter(|s| s.type_id == *type_id) {
+            spatial.remove(id);
+        }
+        if let Some(relation) = self.relation.as_mut().filter(|r| r.type_id == *type_id) {
+            relation.remove(id);
+        }
+        if let Some(sorted) = self.sorted.get_mut(type_id) {
+            sorted.remove(id);
+        }
+        if let Some(backrefs) = self.backrefs.get_mut(type_id) {
+            backrefs.remove(id);
+        }
+        if let Some(dirty) = self.dirty.as_mut().filter(|d| d.type_id == *type_id) {
+            dirty.remove(id);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::custom_errors::CustomError;
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+type Issued = (usize, TypeId, Weak<RefCell<dyn Any>>);
+
+/// How a detected query borrow leak is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeakPolicy {
+    /// Print a warning to stderr and keep going.
+    Warn,
+    /// Panic with `CustomError::QueryResultsHeld`, pointing at the `run` that found the leak.
+    /// `run` has no `Result` to return the error in.
+    Panic,
+}
+
+/// Remembers the cells handed out by the last query run, to notice when a new run starts while
+/// some of them are still held.
+#[derive(Debug)]
+pub struct LeakDetector {
+    policy: LeakPolicy,
+    issued: RefCell<Vec<Issued>>,
+    detected: Cell<usize>,
+}
+
+impl LeakDetector {
+    pub fn new(policy: LeakPolicy) -> Self {
+        Self {
+            policy,
+            issued: RefCell::new(vec![]),
+            detected: Cell::new(0),
+        }
+    }
+
+    /// Reports cells from the previous run that are still held outside of storage. `stored` tells
+    /// whether storage itself holds a cell.
+    pub fn check(&self, stored: impl Fn(usize, &TypeId, &Rc<RefCell<dyn Any>>) -> bool) {
+        let leaked = self
+            .issued
+            .borrow()
+            .iter()
+            .filter(|(id, type_id, cell)| {
+                let holders = cell.strong_count();
+                let Some(cell) = cell.upgrade() else {
+                    return false;
+                };
+                holders > usize::from(stored(*id, type_id, &cell))
+            })
+            .count();
+        if leaked > 0 {
+            self.detected.set(self.detected.get() + 1);
+            let error = CustomError::QueryResultsHeld(leaked);
+            match self.policy {
+                LeakPolicy::Warn => eprintln!("warning: {}", error),
+                LeakPolicy::Panic => panic!("{}", error),
+            }
+        }
+    }
+
+    /// Remembers the cells a run is about to return, replacing those of the previous run.
+    pub fn track<'a>(
+        &self,
+        issued: impl Iterator<Item = (usize, TypeId, &'a Rc<RefCell<dyn Any>>)>,
+    ) {
+        *self.issued.borrow_mut() = issued
+            .map(|(id, type_id, cell)| (id, type_id, Rc::downgrade(cell)))
+            .collect();
+    }
+
+    /// How many runs found leaked results so far.
+    pub fn detected(&self) -> usize {
+        self.detected.get()
+    }
+}
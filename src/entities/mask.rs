@@ -0,0 +1,54 @@
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
+
+/// An entity signature, one bit per registered component. The width caps how many component
+/// types a world can register.
+pub trait Mask:
+    Copy
+    + Eq
+    + Ord
+    + Hash
+    + Default
+    + fmt::Debug
+    + fmt::Binary
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + Not<Output = Self>
+    + BitAndAssign
+    + BitOrAssign
+    + 'static
+{
+    const ZERO: Self;
+    const BITS: u32;
+
+    /// The mask with only bit `index` set.
+    fn bit(index: u32) -> Self;
+    fn count_ones(self) -> u32;
+    fn trailing_zeros(self) -> u32;
+}
+
+macro_rules! impl_mask {
+    ($($mask:ty),*) => {
+        $(
+            impl Mask for $mask {
+                const ZERO: Self = 0;
+                const BITS: u32 = <$mask>::BITS;
+
+                fn bit(index: u32) -> Self {
+                    1 << index
+                }
+
+                fn count_ones(self) -> u32 {
+                    <$mask>::count_ones(self)
+                }
+
+                fn trailing_zeros(self) -> u32 {
+                    <$mask>::trailing_zeros(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_mask!(u32, u64, u128);
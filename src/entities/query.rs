@@ -1,85 +1,802 @@
+use crate::commands::Commands;
 use crate::custom_errors::CustomError;
-use crate::entities::Entities;
+use crate::entities::mask::Mask;
+use crate::entities::{Buffer, Entities, Entity};
+use crate::resources::Resources;
 use eyre::Result;
-use std::any::{Any, TypeId};
-use std::cell::RefCell;
+use std::any::{type_name, Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
-type QueryResult = (Vec<usize>, Vec<Vec<Rc<RefCell<dyn Any>>>>);
+type Component = Rc<RefCell<dyn Any>>;
 
+/// What a query run returns. The columns line up with `ids`: row `i` of every column belongs to
+/// entity `ids[i]`.
 #[derive(Debug)]
-pub struct Query<'a> {
-    map: u32,
-    entities: &'a Entities,
+pub struct QueryResult {
+    /// The matched entity ids. An id is the entity's slot index, and a deleted entity's slot is
+    /// reused by the next entity created, so an id only identifies the same entity until it's
+    /// deleted.
+    pub ids: Vec<usize>,
+    /// A column per selected component, in the order they were added to the query.
+    pub components: Vec<Vec<Component>>,
+    /// The flags requested with `with_presence_flags`, a row per matched entity, or empty if none
+    /// were.
+    pub presence: Vec<Vec<bool>>,
+    /// A column per `with_parent_component`.
+    pub parents: Vec<Vec<Option<Component>>>,
+}
+
+type Predicate<'a> = Box<dyn Fn(&dyn Any) -> bool + 'a>;
+
+/// Produces the value of a column's component for matched entities that lack it.
+struct Fallback<'a>(Box<dyn Fn() -> Component + 'a>);
+
+impl fmt::Debug for Fallback<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fallback").finish_non_exhaustive()
+    }
+}
+
+/// A predicate on the value of one component type.
+struct Filter<'a> {
+    type_id: TypeId,
+    predicate: Predicate<'a>,
+}
+
+impl fmt::Debug for Filter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Filter")
+            .field("type_id", &self.type_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct Query<'a, M: Mask = u64> {
+    map: M,
+    exclusion: M,
+    archetypes: Vec<M>,
+    entities: &'a Entities<M>,
+    resources: Option<&'a Resources>,
+    commands: Option<Commands<'a, M>>,
     type_ids: Vec<TypeId>,
+    buffers: Vec<Buffer>,
+    selected: Vec<TypeId>,
+    string_tags: Vec<String>,
+    presence: Vec<M>,
+    per_archetype: Option<usize>,
+    filters: Vec<Filter<'a>>,
+    updated: Vec<TypeId>,
+    parent_types: Vec<TypeId>,
+    defaults: HashMap<TypeId, Fallback<'a>>,
 }
 
-impl<'a> Query<'a> {
-    pub fn new(entities: &'a Entities) -> Self {
+impl<'a, M: Mask> Query<'a, M> {
+    pub fn new(entities: &'a Entities<M>) -> Self {
         Self {
             entities,
-            map: 0,
+            resources: None,
+            commands: None,
+            map: M::ZERO,
+            exclusion: M::ZERO,
+            archetypes: vec![],
             type_ids: vec![],
+            buffers: vec![],
+            selected: vec![],
+            string_tags: vec![],
+            presence: vec![],
+            per_archetype: None,
+            filters: vec![],
+            updated: vec![],
+            parent_types: vec![],
+            defaults: HashMap::new(),
+        }
+    }
+
+    /// A query over a world's entities that can also fall back to the world's resources, see
+    /// `with_or_resource`, and queues `for_each_ctl` despawns on the world's commands.
+    pub(crate) fn in_world(
+        entities: &'a Entities<M>,
+        resources: &'a Resources,
+        commands: Commands<'a, M>,
+    ) -> Self {
+        Self {
+            resources: Some(resources),
+            commands: Some(commands),
+            ..Self::new(entities)
         }
     }
 
     pub fn with_component<T: Any>(&mut self) -> Result<&mut Self> {
+        self.with_buffered::<T>(Buffer::Current)
+    }
+
+    /// Requires `T` like `with_component`, but reads its column from the given buffer. Components
+    /// that aren't buffered, or haven't been swapped yet, always read the current value.
+    pub fn with_buffered<T: Any>(&mut self, buffer: Buffer) -> Result<&mut Self> {
+        self.with_type_id(TypeId::of::<T>(), buffer)
+    }
+
+    pub(crate) fn with_type_id(&mut self, type_id: TypeId, buffer: Buffer) -> Result<&mut Self> {
+        let bitmask = self.entities.registered_bitmask(&type_id)?;
+        self.map |= bitmask;
+        self.type_ids.push(type_id);
+        self.buffers.push(buffer);
+        Ok(self)
+    }
+
+    /// Gives `T` a result column without requiring it: matched entities lacking `T` get a fresh
+    /// `T::default()` in its place, so the column has a value for every matched entity.
+    pub fn with_or_default<T: Any + Default>(&mut self) -> Result<&mut Self> {
         let type_id = TypeId::of::<T>();
-        match self.entities.get_bitmask(&type_id) {
-            None => return Err(CustomError::ComponentNotRegistered.into()),
-            Some(bitmask) => {
-                // if self.map | bitmask != self.map {
-                self.map |= bitmask;
-                self.type_ids.push(type_id);
-                // }
-            }
+        self.entities.registered_bitmask(&type_id)?;
+        self.type_ids.push(type_id);
+        self.buffers.push(Buffer::Current);
+        self.defaults.insert(
+            type_id,
+            Fallback(Box::new(|| Rc::new(RefCell::new(T::default())))),
+        );
+        Ok(self)
+    }
+
+    /// Gives `T` a dense result column where matched entities lacking the `T` component get a
+    /// clone of the `T` resource instead, a per-entity override of a global default. Without the
+    /// resource `T` is simply required, so this fails with `CustomError::ComponentNotRegistered`
+    /// like `with_component` if `T` isn't a registered component either.
+    pub fn with_or_resource<T: Any + Clone>(&mut self) -> Result<&mut Self> {
+        let type_id = TypeId::of::<T>();
+        let Some(resource) = self
+            .resources
+            .and_then(|resources| resources.get_ref::<T>())
+        else {
+            return self.with_component::<T>();
+        };
+        self.type_ids.push(type_id);
+        self.buffers.push(Buffer::Current);
+        self.defaults.insert(
+            type_id,
+            Fallback(Box::new(move || Rc::new(RefCell::new(resource.clone())))),
+        );
+        Ok(self)
+    }
+
+    /// Requires every component bit in `mask`, as composed from `World::mask_of`. Components
+    /// required this way only take part in matching and get no result column.
+    pub fn require_mask(&mut self, mask: M) -> &mut Self {
+        self.map |= mask;
+        self
+    }
+
+    /// Rejects entities that have component `T`, e.g. everything with a position that isn't
+    /// frozen. Works without any required components too.
+    pub fn without_component<T: Any>(&mut self) -> Result<&mut Self> {
+        let bitmask = self.entities.registered_bitmask(&TypeId::of::<T>())?;
+        Ok(self.exclude_mask(bitmask))
+    }
+
+    /// Rejects entities that have any of the component bits in `mask`.
+    pub fn exclude_mask(&mut self, mask: M) -> &mut Self {
+        self.exclusion |= mask;
+        self
+    }
+
+    /// Only matches entities whose signature is exactly one of `masks`, not merely a superset of
+    /// it. Fails if a mask has a bit no registered component owns. An empty mask matches nothing,
+    /// as free slots never match.
+    pub fn archetype_in(&mut self, masks: &[M]) -> Result<&mut Self> {
+        let registered = self.entities.registered_bits();
+        if masks.iter().any(|&mask| mask & !registered != M::ZERO) {
+            return self.entities.fail(CustomError::ComponentNotRegistered);
         }
+        self.archetypes.extend_from_slice(masks);
         Ok(self)
     }
 
-    pub fn run(&self) -> QueryResult {
-        let indices = self
-            .entities
-            .map
+    /// Only matches entities carrying the string tag `tag`. Tags take part in matching only.
+    pub fn with_string_tag(&mut self, tag: &str) -> &mut Self {
+        self.string_tags.push(tag.to_owned());
+        self
+    }
+
+    /// Makes `run` also report, for each matched entity, whether it has each of the given
+    /// components, in the order given. The components don't take part in matching.
+    pub fn with_presence_flags(&mut self, types: &[TypeId]) -> Result<&mut Self> {
+        for type_id in types {
+            let bitmask = self.entities.registered_bitmask(type_id)?;
+            self.presence.push(bitmask);
+        }
+        Ok(self)
+    }
+
+    /// Only matches entities whose `T` component satisfies `predicate`. Like `require_mask`, `T`
+    /// takes part in matching but gets no result column. The predicate may borrow data, such as a
+    /// resource, that outlives the query.
+    pub fn filter<T: Any>(&mut self, predicate: impl Fn(&T) -> bool + 'a) -> Result<&mut Self> {
+        let type_id = TypeId::of::<T>();
+        self.map |= self.entities.registered_bitmask(&type_id)?;
+        self.filters.push(Filter {
+            type_id,
+            predicate: Box::new(move |component| predicate(component.downcast_ref::<T>().unwrap())),
+        });
+        Ok(self)
+    }
+
+    /// Only matches entities whose `T` component equals `expected`, a `filter` on equality.
+    pub fn with_value<T: Any + PartialEq>(&mut self, expected: T) -> Result<&mut Self> {
+        self.filter(move |value: &T| *value == expected)
+    }
+
+    /// Only matches entities whose `T` component was added or changed since trackers were last
+    /// cleared. `T` takes part in matching but gets no result column.
+    pub fn changed_or_added<T: Any>(&mut self) -> Result<&mut Self> {
+        let type_id = TypeId::of::<T>();
+        self.map |= self.entities.registered_bitmask(&type_id)?;
+        self.updated.push(type_id);
+        Ok(self)
+    }
+
+    /// Makes `run` also return, for each matched entity, the `T` component of its owner through
+    /// the tracked relation, such as its parent's transform. Entities without an owner, or whose
+    /// owner lacks `T`, get `None`. `T` doesn't take part in matching.
+    pub fn with_parent_component<T: Any>(&mut self) -> Result<&mut Self> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        self.parent_types.push(type_id);
+        Ok(self)
+    }
+
+    /// Caps the matches taken from each distinct entity signature at `n`, in id order, so no single
+    /// archetype can crowd out the others.
+    pub fn limit_per_archetype(&mut self, n: usize) -> &mut Self {
+        self.per_archetype = Some(n);
+        self
+    }
+
+    /// Limits the result columns to the selected components. Components that are required but not
+    /// selected still take part in matching, they just aren't cloned into the result. Selecting a
+    /// component that wasn't required yet also requires it. Without any selection every required
+    /// component gets a column.
+    pub fn select<T: Any>(&mut self) -> Result<&mut Self> {
+        let type_id = TypeId::of::<T>();
+        if !self.type_ids.contains(&type_id) {
+            self.with_component::<T>()?;
+        }
+        self.selected.push(type_id);
+        Ok(self)
+    }
+
+    /// Lazily yields the ids of matching entities, scanning only as far as the consumer pulls.
+    /// Nothing is scanned when a required component isn't on any entity.
+    pub fn iter_entities(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut taken = HashMap::<M, usize>::new();
+        let scanned = if self.requires_unpopulated() {
+            0
+        } else {
+            self.entities.map.len()
+        };
+        self.entities.map[..scanned]
             .iter()
             .enumerate()
-            .filter_map(|(index, &entity_map)| {
-                if entity_map & self.map == self.map {
+            .filter_map(move |(index, &entity_map)| {
+                if entity_map != M::ZERO
+                    && entity_map & self.map == self.map
+                    && entity_map & self.exclusion == M::ZERO
+                    && (self.archetypes.is_empty() || self.archetypes.contains(&entity_map))
+                    && self
+                        .string_tags
+                        .iter()
+                        .all(|tag| self.entities.has_tag(index, tag))
+                    && self
+                        .updated
+                        .iter()
+                        .all(|type_id| self.entities.changed_or_added(index, type_id))
+                    && self.filters.iter().all(|filter| {
+                        let component = self.entities.stored(index, &filter.type_id).unwrap();
+                        (filter.predicate)(&*component.borrow())
+                    })
+                {
+                    if let Some(limit) = self.per_archetype {
+                        let count = taken.entry(entity_map).or_default();
+                        if *count == limit {
+                            return None;
+                        }
+                        *count += 1;
+                    }
                     Some(index)
                 } else {
                     None
                 }
             })
-            .collect::<Vec<usize>>();
+    }
+
+    /// Whether a component the query requires by type isn't on any entity, so nothing can match.
+    fn requires_unpopulated(&self) -> bool {
+        self.type_ids
+            .iter()
+            .filter(|type_id| !self.defaults.contains_key(type_id))
+            .chain(self.filters.iter().map(|filter| &filter.type_id))
+            .chain(&self.updated)
+            .any(|type_id| self.entities.populated(type_id) == 0)
+    }
+
+    /// Iterates the matching entities that have `R`, pairing it with their `O` component if they
+    /// have one. `O` doesn't need to be part of the query.
+    pub fn iter_opt<R: Any, O: Any>(
+        &self,
+    ) -> Result<impl Iterator<Item = (Ref<'a, R>, Option<Ref<'a, O>>)> + '_> {
+        let required = TypeId::of::<R>();
+        let optional = TypeId::of::<O>();
+        let required_mask = self.entities.registered_bitmask(&required)?;
+        self.entities.registered_bitmask(&optional)?;
+        let entities = self.entities;
+        Ok(self
+            .iter_entities()
+            .filter(move |&id| entities.map[id] & required_mask == required_mask)
+            .map(move |id| {
+                let required = entities.stored(id, &required).unwrap().borrow();
+                let optional = entities.stored(id, &optional).map(|component| {
+                    Ref::map(component.borrow(), |c| c.downcast_ref::<O>().unwrap())
+                });
+                (
+                    Ref::map(required, |c| c.downcast_ref::<R>().unwrap()),
+                    optional,
+                )
+            }))
+    }
+
+    /// Lazily yields each matching entity that has `T` together with its borrowed `T`, without
+    /// collecting columns first. `T` doesn't need to be part of the query.
+    pub fn iter_entities_components<T: Any>(
+        &self,
+    ) -> Result<impl Iterator<Item = (usize, Ref<'a, T>)> + '_> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        let entities = self.entities;
+        Ok(self.iter_entities().filter_map(move |id| {
+            let component = entities.stored(id, &type_id)?.borrow();
+            Some((id, Ref::map(component, |c| c.downcast_ref::<T>().unwrap())))
+        }))
+    }
+
+    /// Clones the `A` and `B` components of every matching entity into owned pairs that don't
+    /// borrow the world. Both components must be required by the query.
+    pub fn collect_pairs<A: Any + Clone, B: Any + Clone>(&self) -> Result<Vec<(A, B)>> {
+        let first = self.required_type_id::<A>()?;
+        let second = self.required_type_id::<B>()?;
+        Ok(self
+            .iter_entities()
+            .map(|id| {
+                let a = self.entities.stored(id, &first).unwrap().borrow();
+                let b = self.entities.stored(id, &second).unwrap().borrow();
+                (
+                    a.downcast_ref::<A>().unwrap().clone(),
+                    b.downcast_ref::<B>().unwrap().clone(),
+                )
+            })
+            .collect())
+    }
+
+    fn required_type_id<T: Any>(&self) -> Result<TypeId> {
+        let type_id = TypeId::of::<T>();
+        let mask = self.entities.registered_bitmask(&type_id)?;
+        if self.map & mask != mask {
+            return self
+                .entities
+                .fail(CustomError::ComponentNotInQuery(type_name::<T>()));
+        }
+        Ok(type_id)
+    }
+
+    /// Lazily yields a mutable borrow of the `T` component of each matching entity that has one,
+    /// so systems can update components without downcasting by hand. `T` doesn't need to be part
+    /// of the query. Like with `each_mut`, every yielded component is picked up by indexes and
+    /// change tracking once its borrow is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Advancing the iterator panics if the next component is already borrowed, for instance by a
+    /// borrow from a second `iter_mut::<T>` that is still alive.
+    pub fn iter_mut<T: Any>(&self) -> Result<impl Iterator<Item = TrackedMut<'a, T, M>> + '_> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        let entities = self.entities;
+        Ok(self.iter_entities().filter_map(move |id| {
+            let component = entities.stored(id, &type_id)?.borrow_mut();
+            Some(TrackedMut::new(entities, id, component))
+        }))
+    }
+
+    /// Calls `f` with mutable references to both the `A` and the `B` component of every matching
+    /// entity that has both. `A` and `B` don't need to be part of the query, but must be different
+    /// types, or this fails with `CustomError::DuplicateComponentType`. Like `each_mut`, the writes
+    /// are picked up by indexes and change tracking.
+    pub fn for_each2_mut<A: Any, B: Any>(&self, mut f: impl FnMut(&mut A, &mut B)) -> Result<()> {
+        let (first, second) = (TypeId::of::<A>(), TypeId::of::<B>());
+        if first == second {
+            return self
+                .entities
+                .fail(CustomError::DuplicateComponentType(type_name::<A>()));
+        }
+        let mask = self.entities.registered_bitmask(&first)?
+            | self.entities.registered_bitmask(&second)?;
+        for id in self.iter_entities() {
+            if self.entities.map[id] & mask != mask {
+                continue;
+            }
+            let mut a = self.entities.stored(id, &first).unwrap().borrow_mut();
+            let mut b = self.entities.stored(id, &second).unwrap().borrow_mut();
+            f(
+                a.downcast_mut::<A>().unwrap(),
+                b.downcast_mut::<B>().unwrap(),
+            );
+            self.entities.mark_changed(id, &first, &*a);
+            self.entities.mark_changed(id, &second, &*b);
+        }
+        Ok(())
+    }
+
+    /// Lazily yields mutable borrows of the `A` and the `B` component of every matching entity that
+    /// has both, the iterator form of `for_each2_mut`. `A` and `B` must be different types, or this
+    /// fails with `CustomError::DuplicateComponentType`. Like with `iter_mut`, both components are
+    /// picked up by indexes and change tracking once their borrows are dropped.
+    ///
+    /// # Panics
+    ///
+    /// Advancing the iterator panics if either component is already borrowed.
+    pub fn iter2<A: Any, B: Any>(
+        &self,
+    ) -> Result<impl Iterator<Item = (TrackedMut<'a, A, M>, TrackedMut<'a, B, M>)> + '_> {
+        let (first, second) = (TypeId::of::<A>(), TypeId::of::<B>());
+        if first == second {
+            return self
+                .entities
+                .fail(CustomError::DuplicateComponentType(type_name::<A>()));
+        }
+        let mask = self.entities.registered_bitmask(&first)?
+            | self.entities.registered_bitmask(&second)?;
+        let entities = self.entities;
+        Ok(self
+            .iter_entities()
+            .filter(move |&id| entities.map[id] & mask == mask)
+            .map(move |id| {
+                let a = entities.stored(id, &first).unwrap().borrow_mut();
+                let b = entities.stored(id, &second).unwrap().borrow_mut();
+                (
+                    TrackedMut::new(entities, id, a),
+                    TrackedMut::new(entities, id, b),
+                )
+            }))
+    }
+
+    /// Returns one matching entity per distinct `key` of its `T` component, the one with the
+    /// lowest id. Matching entities without `T` are left out.
+    pub fn distinct_by<T: Any, K: Eq + Hash>(&self, key: impl Fn(&T) -> K) -> Result<Vec<usize>> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        let mut seen = HashSet::new();
+        Ok(self
+            .iter_entities()
+            .filter(|&id| match self.entities.stored(id, &type_id) {
+                None => false,
+                Some(component) => {
+                    seen.insert(key(component.borrow().downcast_ref::<T>().unwrap()))
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the `n` matching entities with the highest `key` of their `T` component, highest
+    /// first, ties going to the lower id. Keeps a heap of at most `n` entries instead of sorting
+    /// every match. Matching entities without `T` are left out.
+    pub fn top_n_by<T: Any, K: Ord>(&self, n: usize, key: impl Fn(&T) -> K) -> Result<Vec<usize>> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let mut top = BinaryHeap::with_capacity(n + 1);
+        for id in self.iter_entities() {
+            if let Some(component) = self.entities.stored(id, &type_id) {
+                let key = key(component.borrow().downcast_ref::<T>().unwrap());
+                top.push(Reverse((key, Reverse(id))));
+                if top.len() > n {
+                    top.pop();
+                }
+            }
+        }
+        Ok(top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((_, Reverse(id)))| id)
+            .collect())
+    }
+
+    /// Returns the matched entity ids with their selected components, presence flags and parent
+    /// components.
+    pub fn run(&self) -> QueryResult {
+        self.result(self.iter_entities().collect())
+    }
+
+    /// Like `run`, but with the matched entities as `Entity` handles instead of ids, so they can be
+    /// kept past structural changes: a handle whose entity was deleted since is rejected rather
+    /// than resolving to the slot's next occupant.
+    pub fn run_handles(&self) -> Vec<Entity> {
+        self.iter_entities()
+            .map(|index| self.handle(index))
+            .collect()
+    }
+
+    /// Returns the only matching entity and its row of selected components, for singletons such as
+    /// the player. Fails with `CustomError::ExpectedSingleEntity` unless exactly one entity
+    /// matches.
+    pub fn single(&self) -> Result<(usize, Vec<Component>)> {
+        let indices = self.iter_entities().collect::<Vec<usize>>();
+        if indices.len() != 1 {
+            return self.entities.fail(CustomError::ExpectedSingleEntity {
+                found: indices.len(),
+            });
+        }
+        let id = indices[0];
+        let row = self
+            .column_types()
+            .map(|(type_id, buffer)| self.cell(type_id, buffer, id))
+            .collect();
+        Ok((id, row))
+    }
+
+    /// Like `run`, but with the matched entities in descending id order, so callers can despawn
+    /// while walking the result without disturbing the ids still ahead of them.
+    pub fn run_reversed(&self) -> QueryResult {
+        let mut indices = self.iter_entities().collect::<Vec<usize>>();
+        indices.reverse();
+        self.result(indices)
+    }
+
+    /// Like `run`, but with the matched entities in the order they were spawned rather than by
+    /// id, which slot reuse scrambles.
+    pub fn ordered_by_creation(&self) -> QueryResult {
+        let mut indices = self.iter_entities().collect::<Vec<usize>>();
+        indices.sort_by_key(|&index| self.entities.spawned_at(index));
+        self.result(indices)
+    }
+
+    /// The handle of the entity in slot `index`.
+    fn handle(&self, index: usize) -> Entity {
+        Entity {
+            index,
+            generation: self.entities.generations[index],
+        }
+    }
+
+    fn result(&self, indices: Vec<usize>) -> QueryResult {
+        self.check_leaks();
+        let results = self.columns(&indices);
+        self.track_issued(&indices, &results);
+        let presence = self.presence_flags(&indices);
+        let parents = self.parent_columns(&indices);
+        QueryResult {
+            ids: indices,
+            components: results,
+            presence,
+            parents,
+        }
+    }
+
+    /// Asks the leak detector, if one is enabled, whether the previous run's cells are still held.
+    fn check_leaks(&self) {
+        if let Some(detector) = self.entities.leak_detector() {
+            detector.check(|id, type_id, cell| {
+                self.entities
+                    .stored(id, type_id)
+                    .is_some_and(|stored| Rc::ptr_eq(stored, cell))
+            });
+        }
+    }
 
-        let results = self
-            .type_ids
+    /// Hands the cells about to be returned to the leak detector, if one is enabled.
+    fn track_issued(&self, indices: &[usize], results: &[Vec<Component>]) {
+        if let Some(detector) = self.entities.leak_detector() {
+            let issued = self
+                .column_types()
+                .zip(results)
+                .flat_map(|((type_id, _), column)| {
+                    indices
+                        .iter()
+                        .zip(column)
+                        .map(move |(&id, cell)| (id, *type_id, cell))
+                });
+            detector.track(issued);
+        }
+    }
+
+    fn parent_columns(&self, indices: &[usize]) -> Vec<Vec<Option<Component>>> {
+        self.parent_types
             .iter()
             .map(|type_id| {
-                let components = self.entities.components.get(type_id).unwrap();
                 indices
                     .iter()
-                    .map(|&index| components[index].as_ref().unwrap().clone())
+                    .map(|&index| {
+                        let owner = self.entities.owner(index)?;
+                        self.entities.stored(owner, type_id).cloned()
+                    })
                     .collect()
             })
-            .collect();
+            .collect()
+    }
+
+    fn presence_flags(&self, indices: &[usize]) -> Vec<Vec<bool>> {
+        if self.presence.is_empty() {
+            return vec![];
+        }
+        indices
+            .iter()
+            .map(|&index| {
+                let entity_map = self.entities.map[index];
+                self.presence
+                    .iter()
+                    .map(|&mask| entity_map & mask == mask)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Builds the result columns of the selected components for the given matched entities.
+    fn columns(&self, indices: &[usize]) -> Vec<Vec<Component>> {
+        self.column_types()
+            .map(|(type_id, buffer)| {
+                indices
+                    .iter()
+                    .map(|&index| self.cell(type_id, buffer, index))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The components that get a result column, with the buffer each is read from.
+    fn column_types(&self) -> impl Iterator<Item = (&TypeId, Buffer)> {
+        self.type_ids
+            .iter()
+            .zip(self.buffers.iter().copied())
+            .filter(move |(type_id, _)| self.selected.is_empty() || self.selected.contains(type_id))
+    }
+
+    fn cell(&self, type_id: &TypeId, buffer: Buffer, index: usize) -> Component {
+        match self.defaults.get(type_id) {
+            Some(fallback) if self.entities.stored(index, type_id).is_none() => (fallback.0)(),
+            _ => self
+                .entities
+                .get_buffered(type_id, index, buffer)
+                .unwrap()
+                .clone(),
+        }
+    }
+
+    /// Calls `f` with each matched entity and its row of selected components, in id order. Through
+    /// the `QueryControl` handed to `f`, the current entity can be marked for despawning and the
+    /// scan can be stopped early. When the scan ends, the despawns of a query from `World::query`
+    /// are queued on the world's `Commands`, to be applied by `World::apply_commands` or
+    /// `World::end_frame`. The returned control lists them.
+    pub fn for_each_ctl(
+        &self,
+        mut f: impl FnMut(usize, &[Component], &mut QueryControl),
+    ) -> QueryControl {
+        let mut control = QueryControl::default();
+        for index in self.iter_entities() {
+            let row = self
+                .column_types()
+                .map(|(type_id, buffer)| self.cell(type_id, buffer, index))
+                .collect::<Vec<_>>();
+            control.current = index;
+            f(index, &row, &mut control);
+            if control.stopped {
+                break;
+            }
+        }
+        if let Some(commands) = self.commands {
+            for &index in &control.despawned {
+                commands.despawn(self.handle(index));
+            }
+        }
+        control
+    }
+}
+
+/// A mutable borrow of entity `id`'s `T` component that reports the write to indexes and change
+/// tracking when dropped, as yielded by `Query::iter_mut`.
+pub struct TrackedMut<'a, T: Any, M: Mask = u64> {
+    entities: &'a Entities<M>,
+    id: usize,
+    component: RefMut<'a, T>,
+}
+
+impl<'a, T: Any, M: Mask> TrackedMut<'a, T, M> {
+    fn new(entities: &'a Entities<M>, id: usize, component: RefMut<'a, dyn Any>) -> Self {
+        Self {
+            entities,
+            id,
+            component: RefMut::map(component, |c| c.downcast_mut::<T>().unwrap()),
+        }
+    }
+}
+
+impl<T: Any, M: Mask> Deref for TrackedMut<'_, T, M> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.component
+    }
+}
+
+impl<T: Any, M: Mask> DerefMut for TrackedMut<'_, T, M> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.component
+    }
+}
+
+impl<T: Any, M: Mask> Drop for TrackedMut<'_, T, M> {
+    fn drop(&mut self) {
+        let component: &dyn Any = &*self.component;
+        self.entities
+            .mark_changed(self.id, &TypeId::of::<T>(), component);
+    }
+}
+
+impl<T: Any + fmt::Debug, M: Mask> fmt::Debug for TrackedMut<'_, T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrackedMut")
+            .field("id", &self.id)
+            .field("component", &*self.component)
+            .finish()
+    }
+}
+
+/// Lets a `Query::for_each_ctl` callback steer the scan and record despawns for afterwards.
+#[derive(Debug, Default)]
+pub struct QueryControl {
+    current: usize,
+    stopped: bool,
+    despawned: Vec<usize>,
+}
+
+impl QueryControl {
+    /// Marks the entity currently being visited for despawning once the scan is over.
+    pub fn despawn_current(&mut self) {
+        self.despawned.push(self.current);
+    }
 
-        (indices, results)
+    /// Ends the scan after the current entity.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// The entities marked for despawning, in the order they were visited.
+    pub fn despawned(&self) -> &[usize] {
+        &self.despawned
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::entities::query::Query;
-    use crate::entities::Entities;
+    use crate::custom_errors::CustomError;
+    use crate::entities::query::{Query, QueryResult};
     use eyre::Result;
     use std::any::TypeId;
+    use std::cmp::Reverse;
+    use std::rc::Rc;
+
+    type Entities = crate::entities::Entities;
 
     #[test]
     fn query_mask_updating_with_component() -> Result<()> {
         let mut entities = Entities::default();
-        entities.register_component::<u32>();
-        entities.register_component::<f32>();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
 
         let mut query = Query::new(&entities);
 
@@ -96,8 +813,8 @@ mod tests {
     #[test]
     fn run() -> Result<()> {
         let mut entities = Entities::default();
-        entities.register_component::<u32>();
-        entities.register_component::<f32>();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
 
         entities
             .create_entity()
@@ -117,11 +834,11 @@ mod tests {
             .with_component::<f32>()?
             .run();
 
-        assert_eq!(results.1.len(), 2);
+        assert_eq!(results.components.len(), 2);
 
-        let u32s = &results.1[0];
-        let f32s = &results.1[1];
-        let indices = &results.0;
+        let u32s = &results.components[0];
+        let f32s = &results.components[1];
+        let indices = &results.ids;
 
         assert_eq!(u32s.len(), 2);
         assert_eq!(f32s.len(), 2);
@@ -138,4 +855,907 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn iter_entities() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+
+        entities.create_entity().with_component(10_u32)?;
+        entities.create_entity().with_component(50.0_f32)?;
+        entities.create_entity().with_component(15_u32)?;
+        entities.create_entity().with_component(20_u32)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+
+        let mut matches = query.iter_entities();
+        assert_eq!(matches.next(), Some(0));
+        // Only the first slot has been scanned, the other three are still pending.
+        assert_eq!(matches.size_hint(), (0, Some(3)));
+
+        assert_eq!(query.iter_entities().collect::<Vec<_>>(), vec![0, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn select() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        entities
+            .create_entity()
+            .with_component(10_u32)?
+            .with_component(20.0_f32)?
+            .with_component(1_i8)?;
+        entities
+            .create_entity()
+            .with_component(15_u32)?
+            .with_component(25.0_f32)?;
+        entities
+            .create_entity()
+            .with_component(5_u32)?
+            .with_component(30.0_f32)?
+            .with_component(2_i8)?;
+
+        {
+            let mut query = Query::new(&entities);
+            let results = query
+                .with_component::<u32>()?
+                .with_component::<f32>()?
+                .select::<f32>()?
+                .select::<i8>()?
+                .run();
+
+            assert_eq!(results.ids, vec![0, 2]);
+            assert_eq!(results.components.len(), 2);
+            assert_eq!(
+                results.components[0][1].borrow().downcast_ref::<f32>(),
+                Some(&30.0)
+            );
+            assert_eq!(
+                results.components[1][1].borrow().downcast_ref::<i8>(),
+                Some(&2)
+            );
+        }
+
+        let results = Query::new(&entities)
+            .with_component::<u32>()?
+            .with_component::<f32>()?
+            .with_component::<i8>()?
+            .select::<u32>()?
+            .run();
+
+        assert_eq!(results.components.len(), 1);
+        assert_eq!(results.components[0].len(), 2);
+        assert_eq!(
+            results.components[0][0].borrow().downcast_ref::<u32>(),
+            Some(&10)
+        );
+        let unselected = entities.components[&TypeId::of::<f32>()].get(0);
+        assert_eq!(Rc::strong_count(unselected.unwrap()), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_opt() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        for index in 0..4_u32 {
+            entities.create_entity().with_component(index)?;
+            if index % 2 == 0 {
+                entities.add_component_by_entity_id(index as usize, index as f32)?;
+            }
+        }
+        entities.create_entity().with_component(9.0_f32)?;
+
+        let query = Query::new(&entities);
+        assert!(query.iter_opt::<u32, u64>().is_err());
+
+        let rows = query
+            .iter_opt::<u32, f32>()?
+            .map(|(required, optional)| (*required, optional.map(|optional| *optional)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            rows,
+            vec![(0, Some(0.0)), (1, None), (2, Some(2.0)), (3, None)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_masks() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(1.0_f32)?;
+        entities
+            .create_entity()
+            .with_component(2_u32)?
+            .with_component(2.0_f32)?
+            .with_component(2_i8)?;
+        entities.create_entity().with_component(3_u32)?;
+
+        let mut query = Query::new(&entities);
+        let results = query.require_mask(0b011).exclude_mask(0b100).run();
+
+        assert_eq!(results.ids, vec![0]);
+        assert!(results.components.is_empty());
+
+        let mut typed = Query::new(&entities);
+        let typed = typed
+            .with_component::<u32>()?
+            .with_component::<f32>()?
+            .exclude_mask(0b100)
+            .run();
+
+        assert_eq!(typed.ids, results.ids);
+        assert_eq!(typed.components.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_string_tag() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+
+        for index in 0..3_u32 {
+            entities.create_entity().with_component(index)?;
+        }
+        entities.add_tag(0, "enemy")?;
+        entities.add_tag(2, "enemy")?;
+        entities.add_tag(2, "boss")?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.with_string_tag("enemy");
+        assert_eq!(query.run().ids, vec![0, 2]);
+
+        query.with_string_tag("boss");
+        assert_eq!(query.run().ids, vec![2]);
+
+        let mut query = Query::new(&entities);
+        assert!(query.with_string_tag("unknown").run().ids.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_presence_flags() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(1.0_f32)?;
+        entities
+            .create_entity()
+            .with_component(2_u32)?
+            .with_component(2_i8)?;
+        entities.create_entity().with_component(3_u32)?;
+        entities.create_entity().with_component(4.0_f32)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        assert!(query.run().presence.is_empty());
+
+        let results = query
+            .with_presence_flags(&[TypeId::of::<f32>(), TypeId::of::<i8>()])?
+            .run();
+
+        assert_eq!(results.ids, vec![0, 1, 2]);
+        assert_eq!(
+            results.presence,
+            vec![vec![true, false], vec![false, true], vec![false, false]]
+        );
+        assert!(query.with_presence_flags(&[TypeId::of::<u64>()]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn limit_per_archetype() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+
+        for index in 0..9_u32 {
+            entities.create_entity().with_component(index)?;
+            if index % 3 == 0 {
+                entities.add_component_by_entity_id(index as usize, index as f32)?;
+            }
+        }
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.limit_per_archetype(2);
+        assert_eq!(query.run().ids, vec![0, 1, 2, 3]);
+
+        query.limit_per_archetype(1);
+        assert_eq!(query.iter_entities().collect::<Vec<_>>(), vec![0, 1]);
+
+        query.limit_per_archetype(0);
+        assert!(query.run().ids.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+
+        for index in 0..6_u32 {
+            entities.create_entity().with_component(index)?;
+        }
+        entities.add_component_by_entity_id(4, 4.0_f32)?;
+
+        let threshold = 2;
+        let mut query = Query::new(&entities);
+        query.filter(|value: &u32| *value >= threshold)?;
+        assert_eq!(query.run().ids, vec![2, 3, 4, 5]);
+        assert!(query.run().components.is_empty());
+
+        query
+            .filter(|value: &u32| value.is_multiple_of(2))?
+            .limit_per_archetype(1);
+        assert_eq!(query.run().ids, vec![2, 4]);
+
+        assert!(query.filter(|_: &u64| true).is_err());
+
+        Ok(())
+    }
+
+    #[allow(clippy::float_cmp)]
+    #[test]
+    fn for_each2_mut() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+        entities.register_component::<i8>()?;
+
+        for index in 0..4_u32 {
+            entities.create_entity().with_component(index)?;
+            if index != 2 {
+                entities.add_component_by_entity_id(index as usize, index as f32)?;
+            }
+        }
+        entities.add_component_by_entity_id(3, 1_i8)?;
+        entities.index_component_sorted::<u32>()?;
+        entities.clear_trackers();
+
+        let mut query = Query::new(&entities);
+        assert!(query.for_each2_mut(|_: &mut u32, _: &mut u64| {}).is_err());
+        assert_eq!(
+            query
+                .for_each2_mut(|_: &mut u32, _: &mut u32| {})
+                .unwrap_err()
+                .downcast::<CustomError>()?,
+            CustomError::DuplicateComponentType("u32")
+        );
+
+        query
+            .exclude_mask(0b100)
+            .for_each2_mut(|a: &mut u32, b: &mut f32| {
+                *a += 10;
+                *b *= 2.0;
+            })?;
+
+        assert_eq!(
+            entities.collect_copy::<u32>(),
+            (vec![0, 1, 2, 3], vec![10, 11, 2, 3])
+        );
+        assert_eq!(
+            entities.collect_copy::<f32>(),
+            (vec![0, 1, 3], vec![0.0, 2.0, 3.0])
+        );
+        assert_eq!(entities.query_range(10_u32, 11_u32)?, vec![0, 1]);
+        let mut query = Query::new(&entities);
+        query.changed_or_added::<f32>()?;
+        assert_eq!(query.run().ids, vec![0, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unpopulated_component_skips_scan() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+
+        for index in 0..4_u32 {
+            entities.create_entity().with_component(index)?;
+        }
+        entities.add_component_by_entity_id(2, 2.0_f32)?;
+        entities.delete_component_by_entity_id::<f32>(2)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        assert_eq!(query.iter_entities().size_hint(), (0, Some(4)));
+
+        query.with_component::<f32>()?;
+        assert_eq!(query.iter_entities().size_hint(), (0, Some(0)));
+        assert!(query.run().ids.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn distinct_by() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for material in [3_u32, 1, 3, 2, 1, 7] {
+            entities.create_entity().with_component(material)?;
+        }
+        entities.create_entity().with_component(1_i8)?;
+        entities.add_component_by_entity_id(5, 1_i8)?;
+
+        let query = Query::new(&entities);
+        assert_eq!(
+            query.distinct_by(|material: &u32| *material)?,
+            vec![0, 1, 3, 5]
+        );
+        assert_eq!(
+            query.distinct_by(|material: &u32| material % 2)?,
+            vec![0, 3]
+        );
+        assert!(query.distinct_by(|_: &u64| 0).is_err());
+
+        let mut query = Query::new(&entities);
+        query.exclude_mask(entities.mask_of::<i8>()?);
+        assert_eq!(
+            query.distinct_by(|material: &u32| *material)?,
+            vec![0, 1, 3]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn top_n_by() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for score in [40_u32, 95, 10, 70, 95, 55] {
+            entities.create_entity().with_component(score)?;
+        }
+        entities.create_entity().with_component(1_i8)?;
+
+        let query = Query::new(&entities);
+        assert_eq!(query.top_n_by(3, |score: &u32| *score)?, vec![1, 4, 3]);
+        assert_eq!(
+            query.top_n_by(2, |score: &u32| Reverse(*score))?,
+            vec![2, 0]
+        );
+        assert_eq!(query.top_n_by(10, |score: &u32| *score)?.len(), 6);
+        assert!(query.top_n_by(0, |score: &u32| *score)?.is_empty());
+        assert!(query.top_n_by(1, |_: &u64| 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_parent_component() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<usize>()?;
+        entities.track_relation(|parent: &usize| *parent)?;
+
+        entities.create_entity().with_component(10_u32)?;
+        entities.create_entity().with_component(0_usize)?;
+        entities.create_entity().with_component(1_usize)?;
+        entities.create_entity().with_component(9_usize)?;
+
+        let mut query = Query::new(&entities);
+        query
+            .with_component::<usize>()?
+            .with_parent_component::<u32>()?;
+        let QueryResult { ids, parents, .. } = query.run();
+
+        assert_eq!(ids, vec![1, 2, 3]);
+        let parent = |row: usize| {
+            parents[0][row]
+                .as_ref()
+                .map(|component| *component.borrow().downcast_ref::<u32>().unwrap())
+        };
+        assert_eq!(parent(0), Some(10));
+        assert_eq!(parent(1), None);
+        assert_eq!(parent(2), None);
+        assert!(Query::new(&entities).run().parents.is_empty());
+        assert!(Query::new(&entities)
+            .with_parent_component::<u64>()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_or_default() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+        entities.register_component::<u64>()?;
+
+        entities.create_entity().with_component(1_u32)?;
+        entities
+            .create_entity()
+            .with_component(2_u32)?
+            .with_component(-2_i8)?;
+        entities.create_entity().with_component(3_u32)?;
+        entities.create_entity().with_component(-4_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.with_or_default::<i8>()?;
+        let QueryResult {
+            ids,
+            components: columns,
+            ..
+        } = query.run();
+
+        assert_eq!(ids, vec![0, 1, 2]);
+        let signs = columns[1]
+            .iter()
+            .map(|sign| *sign.borrow().downcast_ref::<i8>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(signs, vec![0, -2, 0]);
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.with_or_default::<u64>()?;
+        assert_eq!(query.run().components[1].len(), 3);
+        assert!(Query::new(&entities).with_or_default::<u16>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_reversed() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for value in 0..5_u32 {
+            entities.create_entity().with_component(value)?;
+        }
+        entities.add_component_by_entity_id(1, 1_i8)?;
+        entities.add_component_by_entity_id(3, 3_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        let forward = query.run();
+        let reversed = query.run_reversed();
+
+        let mut descending = forward.ids.clone();
+        descending.sort_by(|a, b| b.cmp(a));
+        assert_eq!(reversed.ids, descending);
+        let values = reversed.components[0]
+            .iter()
+            .map(|value| *value.borrow().downcast_ref::<u32>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![4, 3, 2, 1, 0]);
+
+        let mut query = Query::new(&entities);
+        query
+            .with_component::<i8>()?
+            .with_presence_flags(&[TypeId::of::<u32>()])?;
+        assert_eq!(query.run_reversed().ids, vec![3, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_handles() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+
+        for value in 0..3_u32 {
+            entities.create_entity().with_component(value)?;
+        }
+
+        let handles = Query::new(&entities).with_component::<u32>()?.run_handles();
+        assert_eq!(
+            handles
+                .iter()
+                .map(|handle| handle.index())
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        let stale = handles[1];
+        entities.delete_by_id(stale)?;
+        entities.create_entity().with_component(10_u32)?;
+
+        let handles = Query::new(&entities).with_component::<u32>()?.run_handles();
+        assert_eq!(handles.len(), 3);
+        assert_eq!(handles[1].index(), stale.index());
+        assert!(!handles.contains(&stale));
+
+        let error = entities.set_component(stale, 11_u32).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::EntityDoesNotExist)
+        );
+        entities.set_component(handles[1], 11_u32)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn with_value() -> Result<()> {
+        #[derive(PartialEq)]
+        enum Team {
+            Red,
+            Blue,
+        }
+
+        let mut entities = Entities::default();
+        entities.register_component::<Team>()?;
+        entities.register_component::<u32>()?;
+
+        for team in [Team::Red, Team::Blue, Team::Red, Team::Blue] {
+            entities
+                .create_entity()
+                .with_component(team)?
+                .with_component(0_u32)?;
+        }
+        entities.create_entity().with_component(1_u32)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.with_value(Team::Red)?;
+        assert_eq!(query.run().ids, vec![0, 2]);
+
+        let mut query = Query::new(&entities);
+        query.with_value(Team::Blue)?;
+        assert_eq!(query.run().ids, vec![1, 3]);
+        assert!(Query::new(&entities).with_value(1_u8).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_entities_components() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for value in [5_u32, 6, 7] {
+            entities.create_entity().with_component(value)?;
+        }
+        entities.create_entity().with_component(-1_i8)?;
+        entities.add_component_by_entity_id(1, 1_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        let lazy = query
+            .iter_entities_components::<u32>()?
+            .map(|(id, value)| (id, *value))
+            .collect::<Vec<_>>();
+        let QueryResult {
+            ids,
+            components: columns,
+            ..
+        } = query.run();
+        let eager = ids
+            .into_iter()
+            .zip(&columns[0])
+            .map(|(id, value)| (id, *value.borrow().downcast_ref::<u32>().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(lazy, eager);
+
+        let signs = query
+            .iter_entities_components::<i8>()?
+            .map(|(id, sign)| (id, *sign))
+            .collect::<Vec<_>>();
+        assert_eq!(signs, vec![(1, 1)]);
+        assert!(query.iter_entities_components::<u64>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn for_each_ctl() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for value in 0..6_u32 {
+            entities
+                .create_entity()
+                .with_component(value)?
+                .with_component(value as i8)?;
+        }
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?.with_component::<i8>()?;
+        let mut visited = vec![];
+        let control = query.for_each_ctl(|id, row, control| {
+            assert_eq!(row.len(), 2);
+            visited.push(id);
+            if *row[0].borrow().downcast_ref::<u32>().unwrap() % 2 == 1 {
+                control.despawn_current();
+            }
+            if id == 4 {
+                control.stop();
+            }
+        });
+
+        assert_eq!(visited, vec![0, 1, 2, 3, 4]);
+        assert_eq!(control.despawned(), &[1, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_by_creation() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for value in 0..3_u32 {
+            entities.create_entity().with_component(value)?;
+        }
+        entities.delete_by_id(0)?;
+        entities.create_entity().with_component(3_u32)?;
+        entities.delete_component_by_entity_id::<u32>(1)?;
+        entities.create_entity().with_component(4_u32)?;
+        entities.add_component_by_entity_id(2, 2_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        assert_eq!(query.run().ids, vec![0, 1, 2]);
+
+        let QueryResult {
+            ids,
+            components: columns,
+            ..
+        } = query.ordered_by_creation();
+        assert_eq!(ids, vec![2, 0, 1]);
+        let values = columns[0]
+            .iter()
+            .map(|value| *value.borrow().downcast_ref::<u32>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![2, 3, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn archetype_in() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+        entities.register_component::<u64>()?;
+
+        entities.create_entity().with_component(0_u32)?;
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(1_i8)?;
+        entities
+            .create_entity()
+            .with_component(2_u32)?
+            .with_component(2_i8)?
+            .with_component(2_u64)?;
+        entities.create_entity().with_component(3_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.archetype_in(&[0b001, 0b111])?;
+        assert_eq!(query.run().ids, vec![0, 2]);
+
+        let mut query = Query::new(&entities);
+        query
+            .with_component::<i8>()?
+            .archetype_in(&[0b011, 0b010])?;
+        assert_eq!(query.run().ids, vec![1, 3]);
+
+        assert!(Query::new(&entities).archetype_in(&[0b1001]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn archetype_in_skips_free_slots() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+
+        entities.create_entity().with_component(0_u32)?;
+        entities.create_entity().with_component(1_u32)?;
+        entities.delete_by_id(0)?;
+
+        let mut query = Query::new(&entities);
+        query.archetype_in(&[0b0, 0b1])?;
+        assert_eq!(query.run().ids, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn single() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+        entities.register_component::<u64>()?;
+
+        entities.create_entity().with_component(0_u32)?;
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(1_i8)?;
+
+        let mut query = Query::new(&entities);
+        query.with_component::<i8>()?.with_component::<u32>()?;
+        let (id, row) = query.single()?;
+        assert_eq!(id, 1);
+        assert_eq!(*row[0].borrow().downcast_ref::<i8>().unwrap(), 1);
+        assert_eq!(*row[1].borrow().downcast_ref::<u32>().unwrap(), 1);
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        assert_eq!(
+            query.single().unwrap_err().downcast::<CustomError>()?,
+            CustomError::ExpectedSingleEntity { found: 2 }
+        );
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u64>()?;
+        assert_eq!(
+            query.single().unwrap_err().downcast::<CustomError>()?,
+            CustomError::ExpectedSingleEntity { found: 0 }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn without_component() -> Result<()> {
+        struct Frozen;
+
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<Frozen>()?;
+        entities.register_component::<i8>()?;
+
+        entities.create_entity().with_component(0_u32)?;
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(Frozen)?;
+        entities.create_entity().with_component(2_i8)?;
+        entities.create_entity().with_component(Frozen)?;
+        entities.create_entity().with_component(3_i8)?;
+        entities.delete_by_id(4)?;
+
+        let mut query = Query::new(&entities);
+        query
+            .with_component::<u32>()?
+            .without_component::<Frozen>()?;
+        assert_eq!(query.run().ids, vec![0]);
+
+        let mut query = Query::new(&entities);
+        query.without_component::<Frozen>()?;
+        assert_eq!(query.run().ids, vec![0, 2]);
+
+        let mut query = Query::new(&entities);
+        query
+            .without_component::<Frozen>()?
+            .without_component::<u32>()?;
+        assert_eq!(query.run().ids, vec![2]);
+        assert!(Query::new(&entities).without_component::<u64>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_mut() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<i8>()?;
+
+        for value in 1..4_u32 {
+            entities.create_entity().with_component(value)?;
+        }
+        entities.add_component_by_entity_id(1, 5_i8)?;
+        entities.index_component_sorted::<u32>()?;
+        entities.clear_trackers();
+
+        let mut query = Query::new(&entities);
+        query.with_component::<u32>()?;
+        for mut value in query.iter_mut::<u32>()? {
+            *value *= 10;
+        }
+        query.iter_mut::<i8>()?.for_each(|mut sign| *sign = -*sign);
+        assert_eq!(entities.query_range(10_u32, 20_u32)?, vec![0, 1]);
+        let mut changed = Query::new(&entities);
+        changed.changed_or_added::<i8>()?;
+        assert_eq!(changed.run().ids, vec![1]);
+
+        let values = query
+            .iter_entities_components::<u32>()?
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![10, 20, 30]);
+        assert_eq!(*entities.try_borrow_component::<i8>(1)?.unwrap(), -5);
+        assert!(query.iter_mut::<u64>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn iter_mut_twice_panics() {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>().unwrap();
+        entities.create_entity().with_component(1_u32).unwrap();
+
+        let query = Query::new(&entities);
+        let _held = query.iter_mut::<u32>().unwrap().next();
+        let _again = query.iter_mut::<u32>().unwrap().next();
+    }
+
+    #[test]
+    fn iter2() -> Result<()> {
+        let mut entities = Entities::default();
+        entities.register_component::<u32>()?;
+        entities.register_component::<f32>()?;
+
+        entities
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(0.5_f32)?;
+        entities.create_entity().with_component(2_u32)?;
+        entities
+            .create_entity()
+            .with_component(3_u32)?
+            .with_component(1.5_f32)?;
+
+        entities.index_component_sorted::<u32>()?;
+        entities.clear_trackers();
+
+        let query = Query::new(&entities);
+        for (mut position, velocity) in query.iter2::<u32, f32>()? {
+            *position += (*velocity * 2.0) as u32;
+        }
+        assert_eq!(entities.query_range(2_u32, 2_u32)?, vec![0, 1]);
+        let mut changed = Query::new(&entities);
+        changed.changed_or_added::<f32>()?;
+        assert_eq!(changed.run().ids, vec![0, 2]);
+
+        let values = query
+            .iter_entities_components::<u32>()?
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![2, 2, 6]);
+        assert_eq!(query.iter2::<f32, u32>()?.count(), 2);
+        assert!(query.iter2::<u32, u64>().is_err());
+        assert_eq!(
+            query
+                .iter2::<u32, u32>()
+                .err()
+                .unwrap()
+                .downcast::<CustomError>()?,
+            CustomError::DuplicateComponentType("u32")
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,90 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+type Extract = Box<dyn Fn(&dyn Any) -> usize>;
+
+/// A reverse index from an owner id, read out of one component type, to the entities pointing at it.
+pub struct Relation {
+    pub(crate) type_id: TypeId,
+    extract: Extract,
+    members: HashMap<usize, Vec<usize>>,
+    owners: HashMap<usize, usize>,
+}
+
+impl Relation {
+    pub fn new<T: Any>(extract: impl Fn(&T) -> usize + 'static) -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            extract: Box::new(move |component| extract(component.downcast_ref::<T>().unwrap())),
+            members: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: usize, component: &dyn Any) {
+        self.remove(id);
+        let owner = (self.extract)(component);
+        let members = self.members.entry(owner).or_default();
+        let position = members
+            .binary_search(&id)
+            .unwrap_or_else(|position| position);
+        members.insert(position, id);
+        self.owners.insert(id, owner);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(owner) = self.owners.remove(&id) {
+            let members = self.members.get_mut(&owner).unwrap();
+            members.retain(|&member| member != id);
+            if members.is_empty() {
+                self.members.remove(&owner);
+            }
+        }
+    }
+
+    /// Members of `owner`, in ascending id order.
+    pub fn members(&self, owner: usize) -> &[usize] {
+        self.members.get(&owner).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn owner(&self, id: usize) -> Option<usize> {
+        self.owners.get(&id).copied()
+    }
+}
+
+impl fmt::Debug for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Relation")
+            .field("type_id", &self.type_id)
+            .field("members", &self.members)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::relation::Relation;
+
+    struct Owner(usize);
+
+    #[test]
+    fn members() {
+        let mut relation = Relation::new(|owner: &Owner| owner.0);
+
+        relation.insert(3, &Owner(0));
+        relation.insert(1, &Owner(0));
+        relation.insert(2, &Owner(5));
+
+        assert_eq!(relation.members(0), &[1, 3]);
+        assert_eq!(relation.members(5), &[2]);
+
+        relation.insert(2, &Owner(0));
+        relation.remove(1);
+
+        assert_eq!(relation.members(0), &[2, 3]);
+        assert!(relation.members(5).is_empty());
+        assert_eq!(relation.owner(2), Some(0));
+        assert_eq!(relation.owner(1), None);
+    }
+}
@@ -0,0 +1,25 @@
+/// Description of one registered component, for tools that render editors without knowing the
+/// component types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentSchema {
+    pub type_name: &'static str,
+    pub bit_index: u32,
+    pub entity_count: usize,
+    /// Empty unless reflection info was registered for the component.
+    pub fields: Vec<FieldSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub type_name: &'static str,
+}
+
+impl FieldSchema {
+    pub fn new(name: &'static str, type_name: &'static str) -> Self {
+        Self { name, type_name }
+    }
+}
+
+/// Lists the fields of a component type.
+pub type Reflect = fn() -> Vec<FieldSchema>;
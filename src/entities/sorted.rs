@@ -0,0 +1,89 @@
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// A type-erased index over the values of one component type.
+pub trait ValueIndex {
+    fn insert(&mut self, id: usize, component: &dyn Any);
+    fn remove(&mut self, id: usize);
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Entity ids ordered by the value of their `T` component.
+pub struct SortedIndex<T> {
+    by_value: BTreeMap<T, BTreeSet<usize>>,
+    values: HashMap<usize, T>,
+}
+
+impl<T: Ord + Clone> SortedIndex<T> {
+    pub fn new() -> Self {
+        Self {
+            by_value: BTreeMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    /// Ids whose value lies in `lo..=hi`, ordered by value and then by id.
+    pub fn range(&self, lo: T, hi: T) -> Vec<usize> {
+        if lo > hi {
+            return vec![];
+        }
+        self.by_value
+            .range(lo..=hi)
+            .flat_map(|(_, ids)| ids.iter().copied())
+            .collect()
+    }
+}
+
+impl<T: Any + Ord + Clone> ValueIndex for SortedIndex<T> {
+    fn insert(&mut self, id: usize, component: &dyn Any) {
+        self.remove(id);
+        let value = component.downcast_ref::<T>().unwrap().clone();
+        self.by_value.entry(value.clone()).or_default().insert(id);
+        self.values.insert(id, value);
+    }
+
+    fn remove(&mut self, id: usize) {
+        if let Some(value) = self.values.remove(&id) {
+            let ids = self.by_value.get_mut(&value).unwrap();
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_value.remove(&value);
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl fmt::Debug for dyn ValueIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueIndex")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::sorted::{SortedIndex, ValueIndex};
+
+    #[test]
+    fn range() {
+        let mut index = SortedIndex::<u32>::new();
+
+        index.insert(0, &150_u32);
+        index.insert(1, &90_u32);
+        index.insert(2, &200_u32);
+        index.insert(3, &150_u32);
+
+        assert_eq!(index.range(100, 200), vec![0, 3, 2]);
+        assert!(index.range(200, 100).is_empty());
+
+        index.insert(0, &50_u32);
+        index.remove(2);
+
+        assert_eq!(index.range(100, 200), vec![3]);
+        assert_eq!(index.range(0, 100), vec![0, 1]);
+    }
+}
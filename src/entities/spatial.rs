@@ -0,0 +1,148 @@
+use crate::custom_errors::CustomError;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+type Extract = Box<dyn Fn(&dyn Any) -> (f32, f32)>;
+
+/// A uniform grid bucketing entities by the position extracted from one component type.
+pub struct SpatialIndex {
+    pub(crate) type_id: TypeId,
+    cell_size: f32,
+    extract: Extract,
+    cells: HashMap<(i64, i64), HashSet<usize>>,
+    positions: HashMap<usize, (f32, f32)>,
+}
+
+impl SpatialIndex {
+    /// Fails with `CustomError::InvalidCellSize` unless `cell_size` is positive and finite.
+    pub fn new<Pos: Any>(
+        cell_size: f32,
+        extract: impl Fn(&Pos) -> (f32, f32) + 'static,
+    ) -> Result<Self, CustomError> {
+        if !(cell_size > 0.0 && cell_size.is_finite()) {
+            return Err(CustomError::InvalidCellSize);
+        }
+        Ok(Self {
+            type_id: TypeId::of::<Pos>(),
+            cell_size,
+            extract: Box::new(move |component| extract(component.downcast_ref::<Pos>().unwrap())),
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+        })
+    }
+
+    fn cell(&self, (x, y): (f32, f32)) -> (i64, i64) {
+        (
+            (x / self.cell_size).floor() as i64,
+            (y / self.cell_size).floor() as i64,
+        )
+    }
+
+    pub fn insert(&mut self, id: usize, component: &dyn Any) {
+        self.remove(id);
+        let position = (self.extract)(component);
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().insert(id);
+        self.positions.insert(id, position);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(position) = self.positions.remove(&id) {
+            let cell = self.cell(position);
+            if let Some(ids) = self.cells.get_mut(&cell) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.positions.clear();
+    }
+
+    /// Ids of the entities positioned inside the inclusive rectangle `min..=max`, in ascending order.
+    /// Rectangles spanning more cells than are occupied scan the occupied cells instead.
+    pub fn query(&self, min: (f32, f32), max: (f32, f32)) -> Vec<usize> {
+        let (min_x, min_y) = self.cell(min);
+        let (max_x, max_y) = self.cell(max);
+        let span = |low: i64, high: i64| (i128::from(high) - i128::from(low) + 1).max(0);
+        let cells: Vec<&HashSet<usize>> = if span(min_x, max_x).saturating_mul(span(min_y, max_y))
+            > self.cells.len() as i128
+        {
+            self.cells
+                .iter()
+                .filter(|((x, y), _)| (min_x..=max_x).contains(x) && (min_y..=max_y).contains(y))
+                .map(|(_, ids)| ids)
+                .collect()
+        } else {
+            (min_x..=max_x)
+                .flat_map(|x| (min_y..=max_y).filter_map(move |y| self.cells.get(&(x, y))))
+                .collect()
+        };
+        let mut ids = vec![];
+        for cell in cells {
+            ids.extend(cell.iter().copied().filter(|id| {
+                let (px, py) = self.positions[id];
+                px >= min.0 && px <= max.0 && py >= min.1 && py <= max.1
+            }));
+        }
+        ids.sort_unstable();
+        ids
+    }
+}
+
+impl fmt::Debug for SpatialIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpatialIndex")
+            .field("type_id", &self.type_id)
+            .field("cell_size", &self.cell_size)
+            .field("cells", &self.cells)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_errors::CustomError;
+    use crate::entities::spatial::SpatialIndex;
+
+    #[derive(Debug)]
+    struct Position(f32, f32);
+
+    #[test]
+    fn query() {
+        let mut index = SpatialIndex::new(10.0, |p: &Position| (p.0, p.1)).unwrap();
+
+        index.insert(0, &Position(1.0, 1.0));
+        index.insert(1, &Position(15.0, 5.0));
+        index.insert(2, &Position(-3.0, 25.0));
+
+        assert_eq!(index.query((0.0, 0.0), (20.0, 20.0)), vec![0, 1]);
+        assert_eq!(index.query((-5.0, 20.0), (0.0, 30.0)), vec![2]);
+
+        index.insert(0, &Position(-1.0, 22.0));
+        index.remove(1);
+
+        assert!(index.query((0.0, 0.0), (20.0, 20.0)).is_empty());
+        assert_eq!(index.query((-5.0, 20.0), (0.0, 30.0)), vec![0, 2]);
+        assert_eq!(index.query((-1e30, -1e30), (1e30, 1e30)), vec![0, 2]);
+        assert_eq!(
+            index.query((f32::MIN, f32::MIN), (f32::MAX, f32::MAX)),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn invalid_cell_size() {
+        for cell_size in [0.0, -10.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                SpatialIndex::new(cell_size, |p: &Position| (p.0, p.1)).unwrap_err(),
+                CustomError::InvalidCellSize
+            );
+        }
+    }
+}
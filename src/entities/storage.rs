@@ -0,0 +1,103 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+type Component = Rc<RefCell<dyn Any>>;
+
+/// Where the values of one component type live, keyed by entity id. Entity signatures decide
+/// which entities have the component; a storage only has to hold the cells.
+pub trait ComponentStorage: fmt::Debug {
+    fn insert(&mut self, id: usize, component: Component);
+    fn remove(&mut self, id: usize) -> Option<Component>;
+    fn get(&self, id: usize) -> Option<&Component>;
+    /// The stored cells in increasing id order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, &Component)> + '_>;
+}
+
+/// A slot per entity id, the default storage. Cheap to index, best for common components.
+#[derive(Default, Debug)]
+pub struct DenseStorage {
+    slots: Vec<Option<Component>>,
+}
+
+impl ComponentStorage for DenseStorage {
+    fn insert(&mut self, id: usize, component: Component) {
+        if id >= self.slots.len() {
+            self.slots.resize(id + 1, None);
+        }
+        self.slots[id] = Some(component);
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Component> {
+        self.slots.get_mut(id)?.take()
+    }
+
+    fn get(&self, id: usize) -> Option<&Component> {
+        self.slots.get(id)?.as_ref()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, &Component)> + '_> {
+        Box::new(
+            self.slots
+                .iter()
+                .enumerate()
+                .filter_map(|(id, slot)| Some((id, slot.as_ref()?))),
+        )
+    }
+}
+
+/// Only holds the entities that have the component, for components few entities carry.
+#[derive(Default, Debug)]
+pub struct SparseStorage {
+    cells: BTreeMap<usize, Component>,
+}
+
+impl ComponentStorage for SparseStorage {
+    fn insert(&mut self, id: usize, component: Component) {
+        self.cells.insert(id, component);
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Component> {
+        self.cells.remove(&id)
+    }
+
+    fn get(&self, id: usize) -> Option<&Component> {
+        self.cells.get(&id)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (usize, &Component)> + '_> {
+        Box::new(self.cells.iter().map(|(&id, component)| (id, component)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entities::storage::{ComponentStorage, DenseStorage, SparseStorage};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn exercise(storage: &mut dyn ComponentStorage) {
+        storage.insert(4, Rc::new(RefCell::new(4_u32)));
+        storage.insert(1, Rc::new(RefCell::new(1_u32)));
+        storage.insert(7, Rc::new(RefCell::new(7_u32)));
+
+        assert!(storage.get(0).is_none());
+        assert!(storage.get(9).is_none());
+        assert!(storage.remove(7).is_some());
+        assert!(storage.remove(7).is_none());
+
+        let stored = storage
+            .iter()
+            .map(|(id, component)| (id, *component.borrow().downcast_ref::<u32>().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(stored, vec![(1, 1), (4, 4)]);
+    }
+
+    #[test]
+    fn dense_and_sparse() {
+        exercise(&mut DenseStorage::default());
+        exercise(&mut SparseStorage::default());
+    }
+}
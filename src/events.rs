@@ -0,0 +1,68 @@
+use std::mem;
+
+/// Messages of type `T` that only matter for a frame or so, such as collisions, kept as a
+/// resource. Each `update` drops the events sent before the previous one, so every event lives
+/// through two updates and a reader running once per frame sees it whether it ran before or
+/// after the sender. Only `World::send_event` creates them, so that `end_frame` updates every one.
+#[derive(Debug)]
+pub struct Events<T> {
+    previous: Vec<T>,
+    current: Vec<T>,
+}
+
+impl<T> Events<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            previous: vec![],
+            current: vec![],
+        }
+    }
+
+    pub fn send(&mut self, event: T) {
+        self.current.push(event);
+    }
+
+    /// The pending events, oldest first, left in place for other readers.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.previous.iter().chain(&self.current)
+    }
+
+    /// Takes the pending events, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.previous.drain(..).chain(self.current.drain(..))
+    }
+
+    /// Drops the events that have already lived through one update.
+    pub fn update(&mut self) {
+        self.previous = mem::take(&mut self.current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::Events;
+
+    #[test]
+    fn update() {
+        let mut events = Events::new();
+        events.send(1);
+        events.update();
+        events.send(2);
+        assert_eq!(events.iter().copied().collect::<Vec<_>>(), vec![1, 2]);
+
+        events.update();
+        assert_eq!(events.iter().copied().collect::<Vec<_>>(), vec![2]);
+        events.update();
+        assert_eq!(events.iter().count(), 0);
+    }
+
+    #[test]
+    fn drain() {
+        let mut events = Events::new();
+        events.send('a');
+        events.update();
+        events.send('b');
+        assert_eq!(events.drain().collect::<String>(), "ab");
+        assert_eq!(events.drain().count(), 0);
+    }
+}
@@ -1,35 +1,111 @@
+mod commands;
 mod custom_errors;
 mod entities;
+mod events;
+mod read_guard;
 mod resources;
+mod schedule;
+mod scoped;
 
-use crate::entities::query::Query;
+use crate::commands::CommandQueue;
+pub use crate::commands::Commands;
+pub use crate::custom_errors::{CustomError, ErrorPolicy};
+pub use crate::entities::builder::EntityBuilder;
+pub use crate::entities::bundle::Bundle;
+pub use crate::entities::cached_query::{CachedQuery, QueryDescriptor};
+pub use crate::entities::leaks::LeakPolicy;
+pub use crate::entities::mask::Mask;
+pub use crate::entities::query::{Query, QueryControl, QueryResult, TrackedMut};
+use crate::entities::schema::Reflect;
+pub use crate::entities::schema::{ComponentSchema, FieldSchema};
+pub use crate::entities::storage::{ComponentStorage, DenseStorage, SparseStorage};
 use crate::entities::Entities;
+pub use crate::entities::{
+    Buffer, CheckedId, Entity, EntityId, MergeStrategy, RemovalPolicy, StructuralEvent,
+};
+pub use crate::events::Events;
+pub use crate::read_guard::ReadGuard;
 use crate::resources::Resources;
+pub use crate::resources::{ResourceTuple, StepRemainder};
+pub use crate::schedule::{Schedule, Stage, System};
+pub use crate::scoped::ScopedWorld;
 use eyre::Result;
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+type DeferredOperation<M> = Box<dyn FnOnce(&mut Entities<M>) -> Result<()>>;
+type Prototype<M> = Box<dyn Fn(&mut EntityBuilder<M>)>;
+type BatchedObserver = Box<dyn FnMut(&[usize])>;
+type UpdateEvents = fn(&mut Resources);
+type CachedComponent = (Weak<RefCell<dyn Any>>, Box<dyn Any>);
+
+/// A world with 64-bit entity signatures, enough for 64 component types. Use `WorldGeneric`
+/// directly to pick another `Mask` width.
+pub type World = WorldGeneric<u64>;
 
 #[derive(Default)]
-pub struct World {
+pub struct WorldGeneric<M: Mask = u64> {
     resources: Resources,
-    entities: Entities,
+    entities: Entities<M>,
+    deferred: Vec<DeferredOperation<M>>,
+    accumulator: f32,
+    prototypes: HashMap<String, Prototype<M>>,
+    on_add: HashMap<TypeId, Vec<BatchedObserver>>,
+    commands: RefCell<CommandQueue<M>>,
+    component_cache: RefCell<HashMap<(usize, TypeId), CachedComponent>>,
+    events: HashMap<TypeId, UpdateEvents>,
 }
 
-impl World {
+impl<M: Mask> WorldGeneric<M> {
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Add a resource.
+    /// Return the world to the state of `World::new()`, dropping all entities, resources and
+    /// component registrations, while keeping the `World` itself in place.
     /// ```
     /// use ecs_lib_rs::World;
     /// let mut world = World::new();
     /// world.add_resource(1_u32);
+    /// world.register_component::<u32>().unwrap();
+    /// world.reset();
+    /// assert_eq!(world.get_resource::<u32>(), None);
+    /// assert!(world.query().with_component::<u32>().is_err());
+    /// ```
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Add a resource. Returns the resource of the same type that was already there, if any,
+    /// which also reveals an accidental second registration.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// assert!(world.add_resource(1_u32).is_none());
     /// assert_eq!(world.get_resource::<u32>(), Some(&1));
+    ///
+    /// let previous = world.add_resource(2_u32).unwrap();
+    /// assert_eq!(previous.downcast_ref::<u32>(), Some(&1));
     /// ```
-    pub fn add_resource(&mut self, resource: impl Any) {
+    pub fn add_resource(&mut self, resource: impl Any) -> Option<Box<dyn Any>> {
         self.resources.add(resource)
     }
 
+    /// Add several resources at once, replacing any already present, e.g. during setup.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.insert_resources((1_u32, 2.5_f32, "title"));
+    /// assert_eq!(world.get_resource::<u32>(), Some(&1));
+    /// assert_eq!(world.get_resource::<f32>(), Some(&2.5));
+    /// assert_eq!(world.get_resource::<&str>(), Some(&"title"));
+    /// ```
+    pub fn insert_resources(&mut self, resources: impl ResourceTuple) {
+        resources.add_to(&mut self.resources)
+    }
+
     /// Query for a resource and get a reference to it. The type of the resource must be added in so that it can find it.
     /// ```
     /// use ecs_lib_rs::World;
@@ -60,6 +136,57 @@ impl World {
         self.resources.get_mut::<T>()
     }
 
+    /// Returns a mutable reference to resource `T`, adding it from `f` first if it's missing.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// *world.get_resource_or_insert_with(|| 0_u32) += 1;
+    /// *world.get_resource_or_insert_with(|| 0_u32) += 1;
+    /// assert_eq!(world.get_resource::<u32>(), Some(&2));
+    /// ```
+    pub fn get_resource_or_insert_with<T: Any>(&mut self, f: impl FnOnce() -> T) -> &mut T {
+        self.resources.get_or_insert_with(f)
+    }
+
+    /// The type of every resource in the world, e.g. for a debug inspector.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// use std::any::TypeId;
+    /// let mut world = World::new();
+    /// world.add_resource(1_u32);
+    /// assert_eq!(
+    ///     world.resource_type_ids().collect::<Vec<_>>(),
+    ///     vec![TypeId::of::<u32>()]
+    /// );
+    /// ```
+    pub fn resource_type_ids(&self) -> impl Iterator<Item = TypeId> + '_ {
+        self.resources.type_ids()
+    }
+
+    /// Whether a resource of type `T` was added.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// assert!(!world.has_resource::<u32>());
+    /// world.add_resource(1_u32);
+    /// assert!(world.has_resource::<u32>());
+    /// ```
+    pub fn has_resource<T: Any>(&self) -> bool {
+        self.resources.contains::<T>()
+    }
+
+    /// Like `add_resource`, but hands back the value that was replaced, if any.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// assert_eq!(world.replace_resource(1_u32), None);
+    /// assert_eq!(world.replace_resource(2_u32), Some(1));
+    /// assert_eq!(world.get_resource::<u32>(), Some(&2));
+    /// ```
+    pub fn replace_resource<T: Any>(&mut self, resource: T) -> Option<T> {
+        self.resources.replace(resource)
+    }
+
     /// Removes the resource from the world. Returns `None` if the resource wasn't present and hence was not deleted.
     /// Otherwise, it returns `Some(data)`
     /// ```   
@@ -73,34 +200,1210 @@ impl World {
         self.resources.remove::<T>()
     }
 
+    /// Copies out resource `T`, to be put back with `restore_resource` if a speculative edit is
+    /// abandoned. Returns `None` if the resource isn't present.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.add_resource(1_u32);
+    /// let saved = world.snapshot_resource::<u32>().unwrap();
+    /// *world.get_resource_mut::<u32>().unwrap() = 5;
+    /// world.restore_resource(saved);
+    /// assert_eq!(world.get_resource::<u32>(), Some(&1));
+    /// ```
+    pub fn snapshot_resource<T: Any + Clone>(&self) -> Option<T> {
+        self.get_resource::<T>().cloned()
+    }
+
+    /// Puts back a resource copied out by `snapshot_resource`, replacing its current value.
+    pub fn restore_resource<T: Any>(&mut self, value: T) {
+        self.add_resource(value);
+    }
+
+    /// In debug builds, check on every `Query::run` whether component cells returned by the
+    /// previous run are still held, a common source of later `RefCell` borrow panics, and report
+    /// it according to `policy`. Pass `None` to turn the check off again.
+    pub fn detect_query_leaks(&mut self, policy: Option<LeakPolicy>) {
+        self.entities.detect_query_leaks(policy)
+    }
+
+    /// How many query runs so far found results of the previous run still held.
+    pub fn query_leaks_detected(&self) -> usize {
+        self.entities.query_leaks_detected()
+    }
+
+    /// Choose whether failing operations panic or return an `Err`. Defaults to `ErrorPolicy::Result`.
+    /// ```
+    /// use ecs_lib_rs::{ErrorPolicy, World};
+    /// use std::panic::AssertUnwindSafe;
+    /// let mut world = World::new();
+    /// assert!(world.delete_entity_by_id(0).is_err());
+    /// world.set_error_policy(ErrorPolicy::Panic);
+    /// let deleted = std::panic::catch_unwind(AssertUnwindSafe(|| world.delete_entity_by_id(0)));
+    /// assert!(deleted.is_err());
+    /// ```
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.entities.set_error_policy(error_policy)
+    }
+
+    /// Lend `value` to the world while `f` runs, e.g. an input device handle that isn't
+    /// `'static`. `f` gets a `ScopedWorld` that derefs to the world and reaches the value through
+    /// `ScopedWorld::scoped`; the world itself never stores it, so nothing can reach it after `f`
+    /// returns.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// struct Input { pressed: Vec<char> }
+    ///
+    /// let mut world = World::new();
+    /// let mut input = Input { pressed: vec!['w'] };
+    /// let pressed = world.with_scoped_resource(&mut input, |world| {
+    ///     world.add_resource(1_u32);
+    ///     world.scoped().pressed.len()
+    /// });
+    /// assert_eq!(pressed, 1);
+    /// ```
+    /// The value can't be smuggled out of the closure:
+    /// ```compile_fail
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// let mut input = vec![1_u8];
+    /// let escaped = world.with_scoped_resource(&mut input, |world| world.scoped());
+    /// ```
+    pub fn with_scoped_resource<T: Any, R>(
+        &mut self,
+        value: &mut T,
+        f: impl FnOnce(&mut ScopedWorld<'_, T, M>) -> R,
+    ) -> R {
+        f(&mut ScopedWorld::new(self, value))
+    }
+
     /// Register a component. The type of the resource must be added in so that it can find it.
+    /// Fails with `CustomError::TooManyComponents` once the world's mask has no bit left for it.
     /// ```
     /// use ecs_lib_rs::World;
     /// let mut world = World::new();
-    /// world.register_component::<u32>();
+    /// world.register_component::<u32>().unwrap();
     ///
     /// ```
-    pub fn register_component<T: Any>(&mut self) {
+    pub fn register_component<T: Any>(&mut self) -> Result<()> {
         self.entities.register_component::<T>()
     }
 
-    pub fn create_entity(&mut self) -> &mut Entities {
+    /// Register a component backed by the given storage instead of the default `DenseStorage`.
+    /// Queries work the same whichever storage each component uses.
+    /// ```
+    /// use ecs_lib_rs::{SparseStorage, World};
+    /// let mut world = World::new();
+    /// world.register_component_with_storage::<u32>(SparseStorage::default()).unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// assert_eq!(world.collect_copy::<u32>(), (vec![0], vec![1]));
+    /// ```
+    pub fn register_component_with_storage<T: Any>(
+        &mut self,
+        storage: impl ComponentStorage + 'static,
+    ) -> Result<()> {
+        self.entities.register_component_with_storage::<T>(storage)
+    }
+
+    /// Register a component under a stable tag, for save files and tooling that can't rely on
+    /// `type_name`. Fails with `CustomError::DuplicateComponentTag` if another component already
+    /// uses the tag.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// use std::any::TypeId;
+    /// let mut world = World::new();
+    /// world.register_component_named::<u32>("health").unwrap();
+    /// assert_eq!(world.component_by_tag("health"), Some(TypeId::of::<u32>()));
+    /// assert_eq!(world.component_tag::<u32>(), Some("health"));
+    /// assert!(world.register_component_named::<f32>("health").is_err());
+    /// ```
+    pub fn register_component_named<T: Any>(&mut self, tag: &'static str) -> Result<()> {
+        self.entities.register_component_named::<T>(tag)
+    }
+
+    pub fn component_tag<T: Any>(&self) -> Option<&'static str> {
+        self.entities.component_tag::<T>()
+    }
+
+    /// The component registered under `tag`, if any.
+    pub fn component_by_tag(&self, tag: &str) -> Option<TypeId> {
+        self.entities.component_by_tag(tag)
+    }
+
+    /// Register a component with a policy for what removing it from an entity does with the value:
+    /// drop it, the default, or retain it hidden for a cheap `reinstate_component`. An entity's
+    /// last component is always dropped, since removing it frees the entity's slot.
+    /// ```
+    /// use ecs_lib_rs::{RemovalPolicy, World};
+    /// let mut world = World::new();
+    /// world.register_component::<bool>().unwrap();
+    /// world.register_component_removal::<u32>(RemovalPolicy::Retain).unwrap();
+    /// world.create_entity().with_component(7_u32).unwrap().with_component(true).unwrap();
+    /// world.delete_component_by_entity_id::<u32>(0).unwrap();
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 0);
+    /// assert!(world.reinstate_component::<u32>(0).unwrap());
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 7);
+    /// ```
+    pub fn register_component_removal<T: Any>(&mut self, policy: RemovalPolicy) -> Result<()> {
+        self.entities.register_component_removal::<T>(policy)
+    }
+
+    /// Gives entity `id` back a `T` retained under `RemovalPolicy::Retain`. Returns whether the
+    /// entity has a `T` afterwards.
+    pub fn reinstate_component<T: Any>(&mut self, id: usize) -> Result<bool> {
+        self.entities.reinstate_component::<T>(id)
+    }
+
+    /// Register a component whose values can be cloned, so entities carrying it can be captured
+    /// by `begin_edit` for undo.
+    pub fn register_cloneable_component<T: Any + Clone>(&mut self) -> Result<()> {
+        self.entities.register_cloneable_component::<T>()
+    }
+
+    /// Attach field descriptions to a registered component, reported by `schema`.
+    pub fn register_reflection<T: Any>(&mut self, reflect: Reflect) -> Result<()> {
+        self.entities.register_reflection::<T>(reflect)
+    }
+
+    /// Describes every registered component: its type name, bit index, how many entities carry
+    /// it, and its fields if reflection info was registered for it. Ordered by bit index.
+    /// ```
+    /// use ecs_lib_rs::{FieldSchema, World};
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.register_reflection::<u32>(|| vec![FieldSchema::new("value", "u32")]).unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    ///
+    /// let schema = world.schema();
+    /// assert_eq!(schema[0].type_name, "u32");
+    /// assert_eq!(schema[0].entity_count, 1);
+    /// assert_eq!(schema[0].fields[0].name, "value");
+    /// ```
+    pub fn schema(&self) -> Vec<ComponentSchema> {
+        self.entities.schema()
+    }
+
+    /// Register a component whose cells remember the entity they belong to, so that a component
+    /// obtained from a query can be mapped back to its owner with `owner_of_component`.
+    pub fn register_component_with_backref<T: Any>(&mut self) -> Result<()> {
+        self.entities.register_component_with_backref::<T>()
+    }
+
+    /// The entity owning the given cell of a component registered with a backref.
+    pub fn owner_of_component<T: Any>(&self, component: &Rc<RefCell<dyn Any>>) -> Option<usize> {
+        self.entities.owner_of_component::<T>(component)
+    }
+
+    /// Register a component that keeps a `previous` copy next to the current one, e.g. for
+    /// interpolating between fixed ticks. Query the old copy with `Query::with_buffered`.
+    pub fn register_buffered_component<T: Any>(&mut self) -> Result<()> {
+        self.entities.register_buffered_component::<T>()
+    }
+
+    /// Copies every current value of the buffered component `T` into its previous buffer.
+    /// Call once per tick before updating the component.
+    pub fn swap_buffers<T: Any + Clone>(&mut self) -> Result<()> {
+        self.entities.swap_buffers::<T>()
+    }
+
+    /// Checks that no two registered components share a bit and that every mask is a single bit.
+    /// Debug builds run this after each registration.
+    pub fn verify_bit_assignment(&self) -> Result<()> {
+        self.entities.verify_bit_assignment()
+    }
+
+    pub fn create_entity(&mut self) -> &mut Entities<M> {
         self.entities.create_entity()
     }
 
-    pub fn query(&self) -> Query {
-        Query::new(&self.entities)
+    /// Returns the ids of entities that have the component `Have` but not `Missing`, e.g. to find
+    /// everything with a position that still needs a collider. Both types must be registered.
+    pub fn entities_missing<Have: Any, Missing: Any>(&self) -> Result<Vec<usize>> {
+        self.entities.entities_missing::<Have, Missing>()
+    }
+
+    /// Returns the entities whose signature includes every component of entity `template_id`,
+    /// including the template itself.
+    pub fn matching_archetype_of(&self, template_id: usize) -> Result<Vec<usize>> {
+        self.entities.matching_archetype_of(template_id)
+    }
+
+    /// Counts the live entities sharing each distinct component signature. Deleted entities
+    /// (signature `0`) are left out.
+    pub fn archetypes(&self) -> HashMap<M, usize> {
+        self.entities.archetypes()
+    }
+
+    /// Calls `f` with the id and a mutable reference for every entity holding the component `T`.
+    /// This walks the component storage directly without collecting ids or cloning `Rc`s, and is
+    /// the recommended way to write to a single component type. Every visited component is
+    /// recorded as changed for `changed_or_added`, and re-indexed if an index is derived from `T`,
+    /// so indexing a component makes this proportionally more expensive.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world.each_mut(|_, value: &mut u32| *value *= 10).unwrap();
+    ///
+    /// let mut values = vec![];
+    /// world.each_mut(|id, value: &mut u32| values.push((id, *value))).unwrap();
+    /// assert_eq!(values, vec![(0, 10), (1, 20)]);
+    /// ```
+    pub fn each_mut<T: Any>(&mut self, f: impl FnMut(usize, &mut T)) -> Result<()> {
+        self.entities.each_mut(f)
+    }
+
+    /// Computes a new value for every `T` from the old one and writes them all back at the end, so
+    /// `f` sees the state from before the update throughout, as double-buffered rules such as
+    /// cellular automata need.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world.map_component(|value: &u32| value * 3).unwrap();
+    /// assert_eq!(world.get_component_or(1, 0_u32).unwrap(), 6);
+    /// ```
+    pub fn map_component<T: Any>(&mut self, f: impl Fn(&T) -> T) -> Result<()> {
+        self.entities.map_component(f)
+    }
+
+    /// Best-effort `each_mut` through a shared borrow. Components currently borrowed elsewhere are
+    /// skipped rather than panicking, and their ids are returned. Writes made this way aren't
+    /// picked up by indexes or change tracking.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    ///
+    /// let held = world.try_borrow_component::<u32>(0).unwrap();
+    /// let skipped = world.each_mut_try(|_, value: &mut u32| *value *= 10).unwrap();
+    /// assert_eq!(skipped, vec![0]);
+    /// drop(held);
+    /// assert_eq!(world.get_component_or(1, 0_u32).unwrap(), 20);
+    /// ```
+    pub fn each_mut_try<T: Any>(&self, f: impl FnMut(usize, &mut T)) -> Result<Vec<usize>> {
+        self.entities.each_mut_try(f)
+    }
+
+    /// Register a named entity template. `build` is run on every spawn, so each entity gets fresh
+    /// component values. Registering a name again replaces the prototype.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.register_prototype("counter", |entity| {
+    ///     entity.with_component(0_u32);
+    /// });
+    /// let id = world.spawn_prototype("counter").unwrap();
+    /// assert_eq!(world.get_component_or(id, 1_u32).unwrap(), 0);
+    /// assert!(world.spawn_prototype("missing").is_err());
+    /// ```
+    pub fn register_prototype(
+        &mut self,
+        name: &str,
+        build: impl Fn(&mut EntityBuilder<M>) + 'static,
+    ) {
+        self.prototypes.insert(name.to_owned(), Box::new(build));
+    }
+
+    /// Spawn an entity from the prototype registered as `name`, returning its id.
+    pub fn spawn_prototype(&mut self, name: &str) -> Result<usize> {
+        let Some(build) = self.prototypes.get(name) else {
+            return self
+                .entities
+                .fail(CustomError::UnknownPrototype(name.to_owned()));
+        };
+        let mut builder = EntityBuilder::default();
+        build(&mut builder);
+        builder.spawn(&mut self.entities)
+    }
+
+    /// Like `each_mut`, but also hands `f` the resource `Res`, borrowed for the whole iteration.
+    /// Fails if the resource hasn't been added.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.add_resource(3_u64);
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world
+    ///     .for_each_with_res(|_, value: &mut u32, factor: &u64| *value *= *factor as u32)
+    ///     .unwrap();
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 6);
+    /// ```
+    pub fn for_each_with_res<T: Any, Res: Any>(
+        &mut self,
+        mut f: impl FnMut(usize, &mut T, &Res),
+    ) -> Result<()> {
+        let resource = match self.resources.get_ref::<Res>() {
+            Some(resource) => resource,
+            None => return self.entities.fail(CustomError::ResourceNotFound),
+        };
+        self.entities
+            .each_mut(|id, component| f(id, component, resource))
+    }
+
+    /// Maintain a grid of the entities holding the component `Pos`, bucketed into square cells of
+    /// `cell_size` using the position returned by `extract`. The grid follows component
+    /// insertions and removals; call `refresh_spatial_index` after moving positions in place.
+    /// Fails with `CustomError::InvalidCellSize` unless `cell_size` is positive and finite.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// struct Position(f32, f32);
+    ///
+    /// let mut world = World::new();
+    /// world.register_component::<Position>().unwrap();
+    /// world.enable_spatial_index(16.0, |p: &Position| (p.0, p.1)).unwrap();
+    /// world.create_entity().with_component(Position(4.0, 4.0)).unwrap();
+    /// world.create_entity().with_component(Position(40.0, 4.0)).unwrap();
+    /// assert_eq!(world.query_region((0.0, 0.0), (10.0, 10.0)), vec![0]);
+    /// ```
+    pub fn enable_spatial_index<Pos: Any>(
+        &mut self,
+        cell_size: f32,
+        extract: impl Fn(&Pos) -> (f32, f32) + 'static,
+    ) -> Result<()> {
+        self.entities.enable_spatial_index(cell_size, extract)
+    }
+
+    /// Track the bounding box of every position, read out of component `Pos` by `extract`, that
+    /// changes during a frame, to limit redraws to it. Both where an entity was and where it went
+    /// count. Like the indexes, it follows insertions, removals, deletions and `each_mut`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// struct Position(f32, f32);
+    ///
+    /// let mut world = World::new();
+    /// world.register_component::<Position>().unwrap();
+    /// world.create_entity().with_component(Position(0.0, 0.0)).unwrap();
+    /// world.track_dirty_region(|p: &Position| (p.0, p.1)).unwrap();
+    /// assert_eq!(world.dirty_region(), None);
+    /// world.add_component_to_entity_by_id(0, Position(4.0, 2.0)).unwrap();
+    /// assert_eq!(world.dirty_region(), Some((0.0, 0.0, 4.0, 2.0)));
+    /// ```
+    pub fn track_dirty_region<Pos: Any>(
+        &mut self,
+        extract: impl Fn(&Pos) -> (f32, f32) + 'static,
+    ) -> Result<()> {
+        self.entities.track_dirty_region(extract)
+    }
+
+    /// The region changed since the last `end_frame`, as `(min_x, min_y, max_x, max_y)`, or
+    /// `None` if nothing moved.
+    pub fn dirty_region(&self) -> Option<(f32, f32, f32, f32)> {
+        self.entities.dirty_region()
+    }
+
+    pub fn refresh_spatial_index(&mut self) {
+        self.entities.refresh_spatial_index()
+    }
+
+    /// Returns the ids of indexed entities inside the inclusive rectangle `min..=max`, or nothing
+    /// if no spatial index was enabled.
+    pub fn query_region(&self, min: (f32, f32), max: (f32, f32)) -> Vec<usize> {
+        self.entities.query_region(min, max)
+    }
+
+    /// Maintain a reverse index from owner ids, read out of component `T` by `extract`, to the
+    /// entities holding such a component. The index follows insertions, removals, deletions and
+    /// `each_mut`; values changed in place through a `RefCell` aren't picked up. Tracking a new
+    /// relation replaces the previous one.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// struct InContainer(usize);
+    ///
+    /// let mut world = World::new();
+    /// world.register_component::<InContainer>().unwrap();
+    /// world.track_relation(|item: &InContainer| item.0).unwrap();
+    /// world.create_entity().with_component(InContainer(7)).unwrap();
+    /// world.create_entity().with_component(InContainer(7)).unwrap();
+    /// assert_eq!(world.related(7), &[0, 1]);
+    /// ```
+    pub fn track_relation<T: Any>(
+        &mut self,
+        extract: impl Fn(&T) -> usize + 'static,
+    ) -> Result<()> {
+        self.entities.track_relation(extract)
+    }
+
+    /// The entities related to `owner` by the tracked relation, in ascending id order.
+    pub fn related(&self, owner: usize) -> Vec<usize> {
+        self.entities.related(owner)
+    }
+
+    /// The owner, e.g. the parent, that entity `id` points at through the tracked relation.
+    pub fn owner(&self, id: usize) -> Option<usize> {
+        self.entities.owner(id)
+    }
+
+    /// Maintain an index of entities ordered by the value of their `T` component, so that
+    /// `query_range` doesn't need to scan. Like the other indexes it follows insertions,
+    /// removals, deletions and `each_mut`, but not values changed in place through a `RefCell`.
+    pub fn index_component_sorted<T: Any + Ord + Clone>(&mut self) -> Result<()> {
+        self.entities.index_component_sorted::<T>()
+    }
+
+    /// Returns the entities whose indexed component `T` lies within `lo..=hi`, ordered by value.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.index_component_sorted::<u32>().unwrap();
+    /// for score in [250_u32, 150, 100, 50] {
+    ///     world.create_entity().with_component(score).unwrap();
+    /// }
+    /// assert_eq!(world.query_range(100_u32, 200).unwrap(), vec![2, 1]);
+    /// ```
+    pub fn query_range<T: Any + Ord + Clone>(&self, lo: T, hi: T) -> Result<Vec<usize>> {
+        self.entities.query_range(lo, hi)
+    }
+
+    /// A counter that advances whenever a component is added to or removed from an entity.
+    pub fn structural_tick(&self) -> u64 {
+        self.entities.structural_tick()
+    }
+
+    /// Runs `f` and advances the structural tick at most once for all the changes it makes, so
+    /// caches keyed on the tick are only invalidated once for a bulk edit.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// let tick = world.structural_tick();
+    /// world.batch(|world| {
+    ///     for value in 0..100_u32 {
+    ///         world.create_entity().with_component(value).unwrap();
+    ///     }
+    /// });
+    /// assert_eq!(world.structural_tick(), tick + 1);
+    /// ```
+    pub fn batch<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let was_batching = self.entities.begin_batch();
+        let guard = BatchGuard {
+            world: self,
+            was_batching,
+        };
+        f(&mut *guard.world)
+    }
+
+    /// Adds `dt` to the world's timestep accumulator and runs `f` once for every whole `fixed`
+    /// interval it holds, carrying the rest over to the next call. The leftover is published as
+    /// the `StepRemainder` resource. Fails with `CustomError::InvalidTimestep` when `dt` is
+    /// negative or not finite, or when `fixed` isn't a positive finite interval.
+    /// ```
+    /// use ecs_lib_rs::{StepRemainder, World};
+    /// let mut world = World::new();
+    /// let mut steps = 0;
+    /// world.step(0.25, 0.1, |_| steps += 1).unwrap();
+    /// assert_eq!(steps, 2);
+    /// let remainder = world.get_resource::<StepRemainder>().unwrap();
+    /// assert!((remainder.alpha - 0.5).abs() < 1e-4);
+    /// assert!(world.step(f32::INFINITY, 0.1, |_| {}).is_err());
+    /// ```
+    pub fn step(&mut self, dt: f32, fixed: f32, mut f: impl FnMut(&mut Self)) -> Result<()> {
+        if !(dt.is_finite() && dt >= 0.0 && fixed.is_finite() && fixed > 0.0) {
+            return self.entities.fail(CustomError::InvalidTimestep);
+        }
+        self.accumulator += dt;
+        while self.accumulator >= fixed {
+            self.accumulator -= fixed;
+            f(self);
+        }
+        self.add_resource(StepRemainder {
+            remainder: self.accumulator,
+            alpha: self.accumulator / fixed,
+        });
+        Ok(())
+    }
+
+    /// The bit of component `T` in entity signatures, for composing masks passed to
+    /// `Query::require_mask` and `Query::exclude_mask`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.register_component::<f32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap().with_component(2.0_f32).unwrap();
+    ///
+    /// let mask = world.mask_of::<u32>().unwrap() | world.mask_of::<f32>().unwrap();
+    /// assert_eq!(world.query().require_mask(mask).run().ids, vec![1]);
+    /// ```
+    pub fn mask_of<T: Any>(&self) -> Result<M> {
+        self.entities.mask_of::<T>()
+    }
+
+    /// How many entities currently hold component `T`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// assert_eq!(world.component_count::<u32>().unwrap(), 2);
+    /// assert!(world.component_count::<f32>().is_err());
+    /// ```
+    pub fn component_count<T: Any>(&self) -> Result<usize> {
+        self.entities.component_count::<T>()
+    }
+
+    /// How many live entities the world holds. An entity whose last component was removed no
+    /// longer counts, since its slot is free to be reused by the next `create_entity`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// assert!(world.is_empty());
+    ///
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world.delete_component_by_entity_id::<u32>(0).unwrap();
+    /// assert_eq!(world.entity_count(), 1);
+    /// assert!(!world.is_empty());
+    /// ```
+    pub fn entity_count(&self) -> usize {
+        self.entities.entity_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Lazily yields the handle of every live entity, for passes that don't fit a query.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world.delete_entity_by_id(0).unwrap();
+    ///
+    /// let ids = world.iter_entities().map(|entity| entity.index()).collect::<Vec<_>>();
+    /// assert_eq!(ids, vec![1]);
+    /// ```
+    pub fn iter_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.iter_entities()
+    }
+
+    /// Enter the read phase of a frame. The returned guard only offers immutable queries and
+    /// lookups, and mutating the world is rejected by the borrow checker while it's alive.
+    /// ```compile_fail
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// let guard = world.read();
+    /// world.create_entity();
+    /// guard.query();
+    /// ```
+    pub fn read(&self) -> ReadGuard<'_, M> {
+        ReadGuard::new(self)
+    }
+
+    /// A handle for queuing spawns, despawns and resource changes through a shared borrow, e.g.
+    /// from a system that only sees `&World`. The queued commands are applied by `end_frame`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.commands().spawn(|entity| {
+    ///     entity.with_component(1_u32);
+    /// });
+    /// assert!(world.query().with_component::<u32>().unwrap().run().ids.is_empty());
+    /// world.end_frame().unwrap();
+    /// assert_eq!(world.query().with_component::<u32>().unwrap().run().ids, vec![0]);
+    /// ```
+    pub fn commands(&self) -> Commands<'_, M> {
+        Commands::new(&self.commands)
+    }
+
+    /// Starts a query over the world's entities. Despawns marked through `Query::for_each_ctl` are
+    /// queued on the world's commands.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// for value in 0..4_u32 {
+    ///     world.create_entity().with_component(value).unwrap();
+    /// }
+    /// world
+    ///     .query()
+    ///     .with_component::<u32>()
+    ///     .unwrap()
+    ///     .for_each_ctl(|_, row, control| {
+    ///         if *row[0].borrow().downcast_ref::<u32>().unwrap() >= 2 {
+    ///             control.despawn_current();
+    ///         }
+    ///     });
+    /// world.apply_commands().unwrap();
+    /// assert_eq!(world.query().with_component::<u32>().unwrap().run().ids, vec![0, 1]);
+    /// ```
+    pub fn query(&self) -> Query<'_, M> {
+        Query::in_world(&self.entities, &self.resources, self.commands())
     }
 
-    pub fn delete_component_by_entity_id<T: Any>(&mut self, id: usize) -> Result<()> {
+    /// Runs a query built by `build` with access to resource `Res`, so filters can close over
+    /// resource data. Fails if the resource hasn't been added.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.add_resource(10_u64);
+    /// world.create_entity().with_component(5_u32).unwrap();
+    /// world.create_entity().with_component(15_u32).unwrap();
+    ///
+    /// let results = world
+    ///     .query_with_resource(|query, limit: &u64| {
+    ///         query.filter(move |value: &u32| u64::from(*value) > *limit)?;
+    ///         Ok(())
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(results.ids, vec![1]);
+    /// ```
+    pub fn query_with_resource<'w, Res: Any>(
+        &'w self,
+        build: impl FnOnce(&mut Query<'w, M>, &'w Res) -> Result<()>,
+    ) -> Result<QueryResult> {
+        let resource = match self.get_resource::<Res>() {
+            Some(resource) => resource,
+            None => return self.entities.fail(CustomError::ResourceNotFound),
+        };
+        let mut query = self.query();
+        build(&mut query, resource)?;
+        Ok(query.run())
+    }
+
+    /// Returns a clone of entity `id`'s component `T`, reading the component only the first time
+    /// it's asked for in a frame and serving later calls from a cache that `end_frame` clears.
+    /// Writes to the component later in the frame aren't seen until then, but replacing or removing
+    /// it, or deleting the entity, is. Returns `None` if the entity doesn't have `T`, and fails like
+    /// `try_borrow_component` when reading the component does.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// assert_eq!(world.cached_component::<u32>(0).unwrap(), Some(1));
+    /// world.set_component(0, 2_u32).unwrap();
+    /// assert_eq!(world.cached_component::<u32>(0).unwrap(), Some(1));
+    /// world.end_frame().unwrap();
+    /// assert_eq!(world.cached_component::<u32>(0).unwrap(), Some(2));
+    /// ```
+    pub fn cached_component<T: Any + Clone>(&self, id: usize) -> Result<Option<T>> {
+        let type_id = TypeId::of::<T>();
+        self.entities.registered_bitmask(&type_id)?;
+        self.entities.entity_at(id)?;
+        let Some(cell) = self.entities.stored(id, &type_id) else {
+            return Ok(None);
+        };
+        let key = (id, type_id);
+        if let Some((source, cached)) = self.component_cache.borrow().get(&key) {
+            if source
+                .upgrade()
+                .is_some_and(|source| Rc::ptr_eq(&source, cell))
+            {
+                return Ok(cached.downcast_ref::<T>().cloned());
+            }
+        }
+        let component = self.entities.try_borrow_component::<T>(id)?;
+        let component = component.as_deref().cloned();
+        if let Some(component) = &component {
+            self.component_cache
+                .borrow_mut()
+                .insert(key, (Rc::downgrade(cell), Box::new(component.clone())));
+        }
+        Ok(component)
+    }
+
+    /// Whether entity `id` has a `T`, without touching the component itself. `false` if the entity
+    /// doesn't exist or `T` isn't registered.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(7_u32).unwrap();
+    ///
+    /// assert!(world.has_component::<u32>(0));
+    /// assert!(!world.has_component::<u32>(1));
+    /// assert!(!world.has_component::<f32>(0));
+    /// ```
+    pub fn has_component<T: Any>(&self, id: usize) -> bool {
+        self.entities.has_component::<T>(id)
+    }
+
+    /// Returns the cell holding entity `id`'s component `T`, without running a query. `None` if the
+    /// entity doesn't exist or doesn't have a `T`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(7_u32).unwrap();
+    ///
+    /// let value = world.get_component::<u32>(0).unwrap();
+    /// *value.borrow_mut().downcast_mut::<u32>().unwrap() += 1;
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 8);
+    /// assert!(world.get_component::<u32>(1).is_none());
+    /// ```
+    pub fn get_component<T: Any>(&self, id: usize) -> Option<Rc<RefCell<dyn Any>>> {
+        self.entities.get_component::<T>(id)
+    }
+
+    /// Returns a clone of entity `id`'s component `T`, or `default` if the entity doesn't have one.
+    /// Errors only if the entity doesn't exist or `T` isn't registered.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.register_component::<f32>().unwrap();
+    /// world.create_entity().with_component(1.5_f32).unwrap();
+    /// assert_eq!(world.get_component_or(0, 7_u32).unwrap(), 7);
+    /// assert_eq!(world.get_component_or(0, 0.0_f32).unwrap(), 1.5);
+    /// ```
+    pub fn get_component_or<T: Any + Clone>(&self, id: usize, default: T) -> Result<T> {
+        self.entities.get_component_or(id, default)
+    }
+
+    /// Borrows entity `id`'s component `T`, or returns `None` if it doesn't have one. Unlike
+    /// borrowing the `RefCell`s returned by a query, a conflicting borrow is reported as a
+    /// `CustomError::ComponentBorrowConflict` naming the entity and component type.
+    pub fn try_borrow_component<T: Any>(&self, id: usize) -> Result<Option<Ref<'_, T>>> {
+        self.entities.try_borrow_component(id)
+    }
+
+    /// The mutable counterpart of `try_borrow_component`.
+    pub fn try_borrow_component_mut<T: Any>(&self, id: usize) -> Result<Option<RefMut<'_, T>>> {
+        self.entities.try_borrow_component_mut(id)
+    }
+
+    /// Copies the component `T` of every entity matched by `query` that has one into a packed
+    /// column, e.g. to hand it to external numeric code. Returns the exported ids alongside the
+    /// values. `query` should come from this world.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<f32>().unwrap();
+    /// world.create_entity().with_component(1.0_f32).unwrap();
+    /// world.create_entity().with_component(2.0_f32).unwrap();
+    ///
+    /// let (ids, mut values) = world
+    ///     .export_column::<f32>(world.query().with_component::<f32>().unwrap())
+    ///     .unwrap();
+    /// values.iter_mut().for_each(|value| *value *= 2.0);
+    /// world.import_column(&ids, &values).unwrap();
+    /// assert_eq!(world.get_component_or(1, 0.0_f32).unwrap(), 4.0);
+    /// ```
+    pub fn export_column<T: Any + Copy>(&self, query: &Query<M>) -> Result<(Vec<usize>, Vec<T>)> {
+        self.entities.export_column(query.iter_entities())
+    }
+
+    /// Copies every `T` in the world into a dense column paired with entity ids, without going
+    /// through a query or cloning any `Rc`s. Empty if `T` isn't registered.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// assert_eq!(world.collect_copy::<u32>(), (vec![0, 1], vec![1, 2]));
+    /// ```
+    pub fn collect_copy<T: Any + Copy>(&self) -> (Vec<usize>, Vec<T>) {
+        self.entities.collect_copy()
+    }
+
+    /// Writes a packed column back by id: `values[i]` becomes component `T` of entity `ids[i]`.
+    pub fn import_column<T: Any + Copy>(&mut self, ids: &[usize], values: &[T]) -> Result<()> {
+        self.entities.import_column(ids, values)
+    }
+
+    pub fn delete_component_by_entity_id<T: Any>(&mut self, id: impl EntityId) -> Result<()> {
         self.entities.delete_component_by_entity_id::<T>(id)
     }
 
-    pub fn add_component_to_entity_by_id(&mut self, id: usize, component: impl Any) -> Result<()> {
+    pub fn add_component_to_entity_by_id(
+        &mut self,
+        id: impl EntityId,
+        component: impl Any,
+    ) -> Result<()> {
         self.entities.add_component_by_entity_id(id, component)
     }
 
-    pub fn delete_entity_by_id(&mut self, id: usize) -> Result<()> {
+    /// Sets entity `id`'s component `T`, writing into the existing cell if the entity has one, so
+    /// `Rc` clones of it held by other systems stay in sync.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    ///
+    /// let held = world.get_component::<u32>(0).unwrap();
+    /// world.set_component(0, 5_u32).unwrap();
+    /// assert_eq!(*held.borrow().downcast_ref::<u32>().unwrap(), 5);
+    /// ```
+    pub fn set_component<T: Any>(&mut self, id: impl EntityId, value: T) -> Result<()> {
+        self.entities.set_component(id, value)
+    }
+
+    /// Queue `component` to be added to entity `id` on the next `flush_deferred`, e.g. while the
+    /// results of a query are still being iterated.
+    pub fn defer_add_component<T: Any>(&mut self, id: usize, component: T) {
+        self.deferred
+            .push(Box::new(move |entities: &mut Entities<M>| {
+                entities.add_component_by_entity_id(id, component)
+            }));
+    }
+
+    /// Applies the queued operations in the order they were queued, stopping at the first one that
+    /// fails. The queue is emptied either way.
+    pub fn flush_deferred(&mut self) -> Result<()> {
+        for operation in std::mem::take(&mut self.deferred) {
+            operation(&mut self.entities)?;
+        }
+        Ok(())
+    }
+
+    /// Tags entity `id` with the string `tag`, so it can be matched with `Query::with_string_tag`.
+    /// An entity can carry any number of tags, and they're cleared when it's deleted.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.add_tag(0, "enemy").unwrap();
+    /// assert_eq!(world.query().with_string_tag("enemy").run().ids, vec![0]);
+    /// ```
+    pub fn add_tag(&mut self, id: usize, tag: &str) -> Result<()> {
+        self.entities.add_tag(id, tag)
+    }
+
+    pub fn remove_tag(&mut self, id: usize, tag: &str) -> Result<()> {
+        self.entities.remove_tag(id, tag)
+    }
+
+    /// Attaches an opaque host handle, such as a pointer cast to `u64`, to entity `id`. Deleting
+    /// the entity drops it.
+    ///
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.set_userdata(0, 42).unwrap();
+    /// assert_eq!(world.userdata(0), Some(42));
+    /// ```
+    pub fn set_userdata(&mut self, id: usize, userdata: u64) -> Result<()> {
+        self.entities.set_userdata(id, userdata)
+    }
+
+    pub fn userdata(&self, id: usize) -> Option<u64> {
+        self.entities.userdata(id)
+    }
+
+    /// Starts an undoable edit by snapshotting every entity's components and tags. Resources
+    /// aren't part of the snapshot. Every live component must have been registered with
+    /// `register_cloneable_component`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_cloneable_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    ///
+    /// world.begin_edit().unwrap();
+    /// world.add_component_to_entity_by_id(0, 2_u32).unwrap();
+    /// world.commit_edit();
+    ///
+    /// world.undo().unwrap();
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 1);
+    /// world.redo().unwrap();
+    /// assert_eq!(world.get_component_or(0, 0_u32).unwrap(), 2);
+    /// ```
+    pub fn begin_edit(&mut self) -> Result<()> {
+        self.entities.begin_edit()
+    }
+
+    pub fn commit_edit(&mut self) {
+        self.entities.commit_edit()
+    }
+
+    pub fn undo(&mut self) -> Result<bool> {
+        self.entities.undo()
+    }
+
+    pub fn redo(&mut self) -> Result<bool> {
+        self.entities.redo()
+    }
+
+    /// Caps how many committed edits can be undone, dropping the oldest beyond it.
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.entities.set_undo_depth(depth)
+    }
+
+    /// Calls `callback` once per frame, at `end_frame`, with the ids of all the entities that
+    /// gained component `T` during the frame. It isn't called for frames where none did.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// let batches = Rc::new(RefCell::new(vec![]));
+    /// let seen = batches.clone();
+    /// world
+    ///     .on_add_batched::<u32>(move |ids| seen.borrow_mut().push(ids.to_vec()))
+    ///     .unwrap();
+    ///
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// world.end_frame().unwrap();
+    /// world.end_frame().unwrap();
+    /// assert_eq!(*batches.borrow(), vec![vec![0, 1]]);
+    /// ```
+    pub fn on_add_batched<T: Any>(
+        &mut self,
+        callback: impl FnMut(&[usize]) + 'static,
+    ) -> Result<()> {
+        self.entities.observe_added::<T>()?;
+        self.on_add
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .push(Box::new(callback));
+        Ok(())
+    }
+
+    /// Ends the current frame: queued commands are applied, batched observers are notified, event
+    /// queues are updated, and the added/changed trackers, the `cached_component` cache and the
+    /// dirty region are cleared. A command that fails doesn't stop the others from being applied;
+    /// the first failure is returned once the frame has ended.
+    pub fn end_frame(&mut self) -> Result<()> {
+        let applied = self.apply_commands();
+        for (type_id, observers) in &mut self.on_add {
+            let gained = self.entities.take_gained(type_id);
+            if !gained.is_empty() {
+                observers.iter_mut().for_each(|observer| observer(&gained));
+            }
+        }
+        for update in self.events.values() {
+            update(&mut self.resources);
+        }
+        self.entities.clear_trackers();
+        self.component_cache.get_mut().clear();
+        self.entities.reset_dirty_region();
+        applied
+    }
+
+    /// Applies the commands queued so far right away, without waiting for `end_frame`, e.g. once
+    /// the query loop that queued them is over. Every command is applied even if one fails, and
+    /// the first failure is returned.
+    pub fn apply_commands(&mut self) -> Result<()> {
+        self.commands.take().apply(self)
+    }
+
+    /// Sends `event` to the readers of `T` events. The `Events<T>` resource is added on the first
+    /// send, and from then on `end_frame` updates it.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// struct Collision(usize, usize);
+    ///
+    /// let mut world = World::new();
+    /// world.send_event(Collision(0, 1));
+    /// world.end_frame().unwrap();
+    /// let collisions = world.read_events::<Collision>().collect::<Vec<_>>();
+    /// assert_eq!(collisions.len(), 1);
+    /// assert_eq!(world.read_events::<Collision>().count(), 1);
+    ///
+    /// world.send_event(Collision(2, 3));
+    /// world.end_frame().unwrap();
+    /// world.end_frame().unwrap();
+    /// assert_eq!(world.read_events::<Collision>().count(), 0);
+    /// ```
+    pub fn send_event<T: Any>(&mut self, event: T) {
+        self.events.entry(TypeId::of::<T>()).or_insert(|resources| {
+            if let Some(events) = resources.get_mut::<Events<T>>() {
+                events.update();
+            }
+        });
+        self.get_resource_or_insert_with(Events::new).send(event);
+    }
+
+    /// The pending `T` events, oldest first. They're left in place, so every reader sees them
+    /// until `end_frame` drops them. Empty if none were ever sent.
+    pub fn read_events<T: Any>(&self) -> impl Iterator<Item = &T> {
+        self.get_resource::<Events<T>>()
+            .into_iter()
+            .flat_map(Events::iter)
+    }
+
+    /// Starts recording spawns, despawns and component additions and removals, for tooling that
+    /// mirrors the world elsewhere. Recording stays on for the life of the world.
+    pub fn record_structural_events(&mut self) {
+        self.entities.record_structural_events()
+    }
+
+    /// Returns the structural events recorded since the last drain, oldest first, and forgets
+    /// them. Nothing is recorded until `record_structural_events` is called.
+    /// ```
+    /// use ecs_lib_rs::{StructuralEvent, World};
+    /// use std::any::TypeId;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.record_structural_events();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// assert_eq!(
+    ///     world.drain_structural_events(),
+    ///     vec![
+    ///         StructuralEvent::EntitySpawned(0),
+    ///         StructuralEvent::ComponentAdded(0, TypeId::of::<u32>()),
+    ///     ]
+    /// );
+    /// assert!(world.drain_structural_events().is_empty());
+    /// ```
+    pub fn drain_structural_events(&mut self) -> Vec<StructuralEvent> {
+        self.entities.drain_structural_events()
+    }
+
+    /// Forgets which components were added or changed, so `Query::changed_or_added` only sees
+    /// what happens afterwards. Call it once per frame.
+    pub fn clear_trackers(&mut self) {
+        self.entities.clear_trackers()
+    }
+
+    /// Rebuilds every entity's signature from the component slots that are actually filled. A
+    /// repair step after editing component storage directly.
+    pub fn recompute_masks(&mut self) {
+        self.entities.recompute_masks()
+    }
+
+    /// Stamps `id` with the current deletion epoch, so it can be held across frames and checked
+    /// with `resolve` before use. Any entity deletion invalidates every outstanding `CheckedId`,
+    /// which conservatively rejects ids that might now be dangling.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.create_entity().with_component(2_u32).unwrap();
+    /// let checked = world.checked_id(1);
+    /// assert_eq!(world.resolve(checked).unwrap(), 1);
+    /// world.delete_entity_by_id(0).unwrap();
+    /// assert!(world.resolve(checked).is_err());
+    /// ```
+    pub fn checked_id(&self, id: usize) -> CheckedId {
+        self.entities.checked_id(id)
+    }
+
+    pub fn resolve(&self, checked: CheckedId) -> Result<usize> {
+        self.entities.resolve(checked)
+    }
+
+    /// Deletes an entity by its id or `Entity` handle. A handle from before the entity's slot was
+    /// deleted or reused is rejected with `CustomError::EntityDoesNotExist`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// let old = world.create_entity().with_component(1_u32).unwrap().entity();
+    /// world.delete_entity_by_id(old).unwrap();
+    ///
+    /// let new = world.create_entity().with_component(2_u32).unwrap().entity();
+    /// assert_eq!(new.index(), old.index());
+    /// assert!(world.delete_entity_by_id(old).is_err());
+    /// assert!(world.add_component_to_entity_by_id(old, 3_u32).is_err());
+    /// assert_eq!(world.get_component_or(new.index(), 0_u32).unwrap(), 2);
+    /// ```
+    pub fn delete_entity_by_id(&mut self, id: impl EntityId) -> Result<()> {
         self.entities.delete_by_id(id)
     }
+
+    /// Reserves `count` entities in one go, e.g. for a particle burst, and returns their ids to add
+    /// components to. Free slots are reused first.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// let ids = world.create_entities(3);
+    /// for &id in &ids {
+    ///     world.add_component_to_entity_by_id(id, id as u32).unwrap();
+    /// }
+    /// assert_eq!(world.collect_copy::<u32>(), (vec![0, 1, 2], vec![0, 1, 2]));
+    /// ```
+    pub fn create_entities(&mut self, count: usize) -> Vec<usize> {
+        self.entities.create_entities(count)
+    }
+
+    /// Deletes every entity and its components, keeping resources and registered components, e.g.
+    /// to restart a level.
+    pub fn clear_entities(&mut self) {
+        self.entities.clear_entities()
+    }
+
+    /// The handle of the entity currently in slot `id`, to keep instead of the bare id.
+    pub fn entity_at(&self, id: usize) -> Result<Entity> {
+        self.entities.entity_at(id)
+    }
+
+    /// Moves the entities of `other` into this world along with their components and tags, and
+    /// returns the id each of them got here, keyed by its id in `other`. `strategy` decides what
+    /// happens to ids already taken here. Resources of `other` are dropped, and every component
+    /// it uses must be registered in this world.
+    /// ```
+    /// use ecs_lib_rs::{MergeStrategy, World};
+    /// let mut scene = World::new();
+    /// scene.register_component::<u32>().unwrap();
+    /// scene.create_entity().with_component(1_u32).unwrap();
+    ///
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(0_u32).unwrap();
+    ///
+    /// let placed = world.merge_with(scene, MergeStrategy::Remap).unwrap();
+    /// assert_eq!(placed[&0], 1);
+    /// assert_eq!(world.get_component_or(1, 0_u32).unwrap(), 1);
+    /// ```
+    pub fn merge_with(
+        &mut self,
+        other: WorldGeneric<M>,
+        strategy: MergeStrategy,
+    ) -> Result<HashMap<usize, usize>> {
+        self.entities.merge(other.entities, strategy)
+    }
+
+    /// Deletes entity `id`, recording why in the despawn log once it has been enabled with
+    /// `set_despawn_log_cap`.
+    /// ```
+    /// use ecs_lib_rs::World;
+    /// let mut world = World::new();
+    /// world.register_component::<u32>().unwrap();
+    /// world.create_entity().with_component(1_u32).unwrap();
+    /// world.set_despawn_log_cap(16);
+    /// world.despawn_with_reason(0, "out of bounds").unwrap();
+    /// assert_eq!(world.despawn_log(), &[(0, "out of bounds".to_owned())]);
+    /// ```
+    pub fn despawn_with_reason(&mut self, id: usize, reason: &str) -> Result<()> {
+        self.entities.despawn_with_reason(id, reason)
+    }
+
+    pub fn set_despawn_log_cap(&mut self, cap: usize) {
+        self.entities.set_despawn_log_cap(cap)
+    }
+
+    pub fn despawn_log(&self) -> &[(usize, String)] {
+        self.entities.despawn_log()
+    }
+}
+
+/// Ends the batch `WorldGeneric::batch` started when dropped, so a panicking closure doesn't
+/// leave the world batching.
+struct BatchGuard<'a, M: Mask> {
+    world: &'a mut WorldGeneric<M>,
+    was_batching: bool,
+}
+
+impl<M: Mask> Drop for BatchGuard<'_, M> {
+    fn drop(&mut self) {
+        self.world.entities.end_batch(self.was_batching);
+    }
 }
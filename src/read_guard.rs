@@ -0,0 +1,72 @@
+use crate::commands::Commands;
+use crate::entities::mask::Mask;
+use crate::entities::query::Query;
+use crate::WorldGeneric;
+use eyre::Result;
+use std::any::Any;
+use std::cell::Ref;
+
+/// Read-only view of a `World`, obtained from `World::read`. It only offers immutable queries and
+/// lookups, and holds a shared borrow of the world for as long as it lives, so any number of
+/// guards and query results can coexist while mutating the world is rejected at compile time.
+pub struct ReadGuard<'a, M: Mask = u64> {
+    world: &'a WorldGeneric<M>,
+}
+
+impl<M: Mask> Clone for ReadGuard<'_, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Mask> Copy for ReadGuard<'_, M> {}
+
+impl<'a, M: Mask> ReadGuard<'a, M> {
+    pub(crate) fn new(world: &'a WorldGeneric<M>) -> Self {
+        Self { world }
+    }
+
+    pub fn get_resource<T: Any>(&self) -> Option<&'a T> {
+        self.world.get_resource::<T>()
+    }
+
+    pub fn commands(&self) -> Commands<'a, M> {
+        self.world.commands()
+    }
+
+    pub fn query(&self) -> Query<'a, M> {
+        self.world.query()
+    }
+
+    pub fn mask_of<T: Any>(&self) -> Result<M> {
+        self.world.mask_of::<T>()
+    }
+
+    pub fn component_count<T: Any>(&self) -> Result<usize> {
+        self.world.component_count::<T>()
+    }
+
+    pub fn get_component_or<T: Any + Clone>(&self, id: usize, default: T) -> Result<T> {
+        self.world.get_component_or(id, default)
+    }
+
+    pub fn try_borrow_component<T: Any>(&self, id: usize) -> Result<Option<Ref<'a, T>>> {
+        self.world.try_borrow_component(id)
+    }
+
+    pub fn entities_missing<Have: Any, Missing: Any>(&self) -> Result<Vec<usize>> {
+        self.world.entities_missing::<Have, Missing>()
+    }
+
+    pub fn query_region(&self, min: (f32, f32), max: (f32, f32)) -> Vec<usize> {
+        self.world.query_region(min, max)
+    }
+
+    pub fn related(&self, owner: usize) -> Vec<usize> {
+        self.world.related(owner)
+    }
+
+    pub fn structural_tick(&self) -> u64 {
+        self.world.structural_tick()
+    }
+}
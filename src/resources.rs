@@ -1,15 +1,24 @@
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 
-#[derive(Default)]
+/// Resource holding the time `World::step` has accumulated but not yet consumed, and that
+/// remainder as a fraction of the fixed interval, for interpolating between the last two steps.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StepRemainder {
+    pub remainder: f32,
+    pub alpha: f32,
+}
+
+#[derive(Default, Debug)]
 pub struct Resources {
     data: HashMap<TypeId, Box<dyn Any>>,
 }
 
 impl Resources {
-    pub fn add(&mut self, data: impl Any) {
+    /// Stores `data`, returning the resource of the same type it displaced, if any.
+    pub fn add(&mut self, data: impl Any) -> Option<Box<dyn Any>> {
         let type_id = data.type_id();
-        self.data.insert(type_id, Box::new(data));
+        self.data.insert(type_id, Box::new(data))
     }
 
     pub fn get_ref<T: Any>(&self) -> Option<&T> {
@@ -28,11 +37,62 @@ impl Resources {
         None
     }
 
+    /// Returns resource `T`, first adding `f()` if it isn't present.
+    pub fn get_or_insert_with<T: Any>(&mut self, f: impl FnOnce() -> T) -> &mut T {
+        self.data
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .unwrap()
+    }
+
+    pub fn contains<T: Any>(&self) -> bool {
+        self.data.contains_key(&TypeId::of::<T>())
+    }
+
+    /// The type of every stored resource, in no particular order.
+    pub fn type_ids(&self) -> impl Iterator<Item = TypeId> + '_ {
+        self.data.keys().copied()
+    }
+
     pub fn remove<T: Any>(&mut self) -> Option<Box<dyn Any>> {
         self.data.remove(&TypeId::of::<T>())
     }
+
+    /// Stores `data`, returning the value it replaced.
+    pub fn replace<T: Any>(&mut self, data: T) -> Option<T> {
+        let previous = self.data.insert(TypeId::of::<T>(), Box::new(data))?;
+        Some(*previous.downcast::<T>().unwrap())
+    }
 }
 
+/// A tuple of up to eight resources that `World::insert_resources` adds in one call, left to
+/// right, so a later resource of the same type overwrites an earlier one.
+pub trait ResourceTuple {
+    fn add_to(self, resources: &mut Resources);
+}
+
+macro_rules! impl_resource_tuple {
+    ($($resource:ident),*) => {
+        impl<$($resource: Any),*> ResourceTuple for ($($resource,)*) {
+            #[allow(non_snake_case)]
+            fn add_to(self, resources: &mut Resources) {
+                let ($($resource,)*) = self;
+                $(resources.add($resource);)*
+            }
+        }
+    };
+}
+
+impl_resource_tuple!(A);
+impl_resource_tuple!(A, B);
+impl_resource_tuple!(A, B, C);
+impl_resource_tuple!(A, B, C, D);
+impl_resource_tuple!(A, B, C, D, E);
+impl_resource_tuple!(A, B, C, D, E, F);
+impl_resource_tuple!(A, B, C, D, E, F, G);
+impl_resource_tuple!(A, B, C, D, E, F, G, H);
+
 #[allow(clippy::float_cmp)]
 #[cfg(test)]
 mod tests {
@@ -54,6 +114,15 @@ mod tests {
         assert_eq!(extracted_world_width.0, 100.0_f32);
     }
 
+    #[test]
+    fn add_returns_previous() {
+        let mut resources = Resources::default();
+        assert!(resources.add(WorldWidth(100.0)).is_none());
+        let previous = resources.add(WorldWidth(200.0)).unwrap();
+        assert_eq!(previous.downcast_ref(), Some(&WorldWidth(100.0)));
+        assert_eq!(resources.get_ref::<WorldWidth>(), Some(&WorldWidth(200.0)));
+    }
+
     #[test]
     fn get_resource() {
         let mut resources = Resources::default();
@@ -64,6 +133,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::explicit_auto_deref)]
     fn get_mut() {
         let mut resources = Resources::default();
         let world_width = WorldWidth(100.0);
@@ -79,6 +149,38 @@ mod tests {
     }
 
     #[test]
+    fn get_or_insert_with() {
+        let mut resources = Resources::default();
+        resources.get_or_insert_with(|| WorldWidth(100.0)).0 += 1.0;
+        let world_width = resources.get_or_insert_with::<WorldWidth>(|| unreachable!());
+        assert_eq!(world_width, &mut WorldWidth(101.0));
+    }
+
+    #[test]
+    fn contains() {
+        let mut resources = Resources::default();
+        assert!(!resources.contains::<WorldWidth>());
+        resources.add(WorldWidth(100.0));
+        assert!(resources.contains::<WorldWidth>());
+        resources.remove::<WorldWidth>();
+        assert!(!resources.contains::<WorldWidth>());
+    }
+
+    #[test]
+    fn type_ids() {
+        let mut resources = Resources::default();
+        assert_eq!(resources.type_ids().count(), 0);
+        resources.add(WorldWidth(100.0));
+        resources.add(1_u32);
+        let mut type_ids = resources.type_ids().collect::<Vec<_>>();
+        type_ids.sort();
+        let mut expected = vec![TypeId::of::<WorldWidth>(), TypeId::of::<u32>()];
+        expected.sort();
+        assert_eq!(type_ids, expected);
+    }
+
+    #[test]
+    #[allow(clippy::type_id_on_box)]
     fn remove() {
         let mut resources = Resources::default();
         let world_width = WorldWidth(100.0);
@@ -88,4 +190,15 @@ mod tests {
             Some((Box::new(WorldWidth(100.0)) as Box<dyn Any>).type_id())
         );
     }
+
+    #[test]
+    fn replace() {
+        let mut resources = Resources::default();
+        assert_eq!(resources.replace(WorldWidth(100.0)), None);
+        assert_eq!(
+            resources.replace(WorldWidth(200.0)),
+            Some(WorldWidth(100.0))
+        );
+        assert_eq!(resources.get_ref::<WorldWidth>(), Some(&WorldWidth(200.0)));
+    }
 }
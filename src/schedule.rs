@@ -0,0 +1,160 @@
+use crate::entities::mask::Mask;
+use crate::WorldGeneric;
+use std::collections::BTreeMap;
+
+/// Game logic that runs over the world each time its `Schedule` runs.
+pub trait System<M: Mask = u64> {
+    fn run(&mut self, world: &mut WorldGeneric<M>);
+}
+
+/// A closure added with `Schedule::add_fn`.
+struct FnSystem<F>(F);
+
+impl<M: Mask, F: FnMut(&mut WorldGeneric<M>)> System<M> for FnSystem<F> {
+    fn run(&mut self, world: &mut WorldGeneric<M>) {
+        (self.0)(world)
+    }
+}
+
+/// A group of systems. Every system of an earlier stage runs before any system of a later one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    PreUpdate,
+    Update,
+    PostUpdate,
+}
+
+/// The systems to run on every tick, stage by stage, and within a stage in the order they were
+/// added.
+pub struct Schedule<M: Mask = u64> {
+    stages: BTreeMap<Stage, Vec<Box<dyn System<M>>>>,
+}
+
+impl<M: Mask> Default for Schedule<M> {
+    fn default() -> Self {
+        Self {
+            stages: BTreeMap::new(),
+        }
+    }
+}
+
+impl<M: Mask> Schedule<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a system to the `Update` stage.
+    pub fn add(&mut self, system: impl System<M> + 'static) -> &mut Self {
+        self.add_to_stage(Stage::Update, system)
+    }
+
+    pub fn add_to_stage(&mut self, stage: Stage, system: impl System<M> + 'static) -> &mut Self {
+        self.stages.entry(stage).or_default().push(Box::new(system));
+        self
+    }
+
+    /// Adds a closure as a system to the `Update` stage, for logic too small to deserve a type.
+    /// The closure may own state that it keeps across runs.
+    pub fn add_fn(&mut self, system: impl FnMut(&mut WorldGeneric<M>) + 'static) -> &mut Self {
+        self.add(FnSystem(system))
+    }
+
+    /// Runs every system once over `world`.
+    pub fn run(&mut self, world: &mut WorldGeneric<M>) {
+        for system in self.stages.values_mut().flatten() {
+            system.run(world);
+        }
+    }
+
+    /// Runs the whole schedule `steps` times in a row, e.g. the number of fixed physics steps
+    /// that fit into this frame. Nothing is reset between steps: resources and components a step
+    /// changes are what the next step sees.
+    pub fn run_fixed(&mut self, world: &mut WorldGeneric<M>, steps: usize) {
+        for _ in 0..steps {
+            self.run(world);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schedule::{Schedule, Stage, System};
+    use crate::World;
+
+    struct Count;
+
+    impl System for Count {
+        fn run(&mut self, world: &mut World) {
+            *world.get_resource_mut::<u32>().unwrap() += 1;
+        }
+    }
+
+    struct Double;
+
+    impl System for Double {
+        fn run(&mut self, world: &mut World) {
+            *world.get_resource_mut::<u32>().unwrap() *= 2;
+        }
+    }
+
+    #[test]
+    fn run() {
+        let mut world = World::new();
+        world.add_resource(1_u32);
+
+        let mut schedule = Schedule::new();
+        schedule.add(Count).add(Double);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<u32>(), Some(&4));
+
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<u32>(), Some(&10));
+    }
+
+    #[test]
+    fn add_fn() {
+        let mut world = World::new();
+        world.add_resource(0_u32);
+
+        let mut runs = 0_u32;
+        let mut schedule = Schedule::new();
+        schedule
+            .add_fn(move |world: &mut World| {
+                runs += 1;
+                world.add_resource(runs);
+            })
+            .add(Double);
+        schedule.run(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<u32>(), Some(&4));
+    }
+
+    #[test]
+    fn stages() {
+        let mut world = World::new();
+        world.add_resource(1_u32);
+
+        let mut schedule = Schedule::new();
+        schedule
+            .add_to_stage(Stage::PostUpdate, Double)
+            .add(Count)
+            .add_to_stage(Stage::PreUpdate, Double)
+            .add_to_stage(Stage::PostUpdate, Count);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<u32>(), Some(&7));
+    }
+
+    #[test]
+    fn run_fixed() {
+        let mut world = World::new();
+        world.add_resource(1_u32);
+
+        let mut schedule = Schedule::new();
+        schedule.add(Count);
+        schedule.run_fixed(&mut world, 3);
+        assert_eq!(world.get_resource::<u32>(), Some(&4));
+
+        schedule.run_fixed(&mut world, 0);
+        assert_eq!(world.get_resource::<u32>(), Some(&4));
+    }
+}
@@ -0,0 +1,36 @@
+use crate::entities::mask::Mask;
+use crate::WorldGeneric;
+use std::ops::{Deref, DerefMut};
+
+/// The world together with a value lent to it by `World::with_scoped_resource`. It derefs to the
+/// world, and since it's only ever handed to the closure by reference, neither it nor the lent
+/// value can be kept once the closure returns.
+pub struct ScopedWorld<'a, T, M: Mask = u64> {
+    world: &'a mut WorldGeneric<M>,
+    value: &'a mut T,
+}
+
+impl<'a, T, M: Mask> ScopedWorld<'a, T, M> {
+    pub(crate) fn new(world: &'a mut WorldGeneric<M>, value: &'a mut T) -> Self {
+        Self { world, value }
+    }
+
+    /// The lent value.
+    pub fn scoped(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T, M: Mask> Deref for ScopedWorld<'_, T, M> {
+    type Target = WorldGeneric<M>;
+
+    fn deref(&self) -> &Self::Target {
+        self.world
+    }
+}
+
+impl<T, M: Mask> DerefMut for ScopedWorld<'_, T, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.world
+    }
+}
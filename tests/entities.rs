@@ -1,22 +1,27 @@
 #[cfg(test)]
 mod tests {
-    use ecs_lib_rs::World;
+    use ecs_lib_rs::{
+        Buffer, CachedQuery, ComponentSchema, CustomError, DenseStorage, FieldSchema, Mask,
+        MergeStrategy, QueryDescriptor, QueryResult, ReadGuard, SparseStorage, StructuralEvent,
+        World, WorldGeneric,
+    };
     use eyre::Result;
-    use std::any::Any;
+    use std::any::{Any, TypeId};
     use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::rc::Rc;
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, Clone, PartialEq)]
     struct Location(f32, f32);
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, Clone, PartialEq)]
     struct Size(f32);
 
     #[test]
     fn create_entity() -> Result<()> {
         let mut world = World::new();
 
-        world.register_component::<Location>();
-        world.register_component::<Size>();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
 
         world
             .create_entity()
@@ -30,8 +35,8 @@ mod tests {
     fn query_entities() -> Result<()> {
         let mut world = World::new();
 
-        world.register_component::<Location>();
-        world.register_component::<Size>();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
 
         world
             .create_entity()
@@ -53,8 +58,8 @@ mod tests {
             .with_component::<Size>()?
             .run();
 
-        let locations: &Vec<Rc<RefCell<dyn Any>>> = &results.1[0];
-        let sizes: &Vec<Rc<RefCell<dyn Any>>> = &results.1[1];
+        let locations: &Vec<Rc<RefCell<dyn Any>>> = &results.components[0];
+        let sizes: &Vec<Rc<RefCell<dyn Any>>> = &results.components[1];
 
         assert_eq!(sizes.len(), 2);
         assert_eq!(locations.len(), 2);
@@ -82,8 +87,8 @@ mod tests {
     fn delete_component_from_entity() -> Result<()> {
         let mut world = World::new();
 
-        world.register_component::<Location>();
-        world.register_component::<Size>();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
 
         world
             .create_entity()
@@ -102,8 +107,8 @@ mod tests {
             .with_component::<Size>()?
             .run();
 
-        assert_eq!(results.0.len(), 1);
-        assert_eq!(results.0[0], 1);
+        assert_eq!(results.ids.len(), 1);
+        assert_eq!(results.ids[0], 1);
 
         Ok(())
     }
@@ -112,8 +117,8 @@ mod tests {
     fn add_component_to_entity_by_id() -> Result<()> {
         let mut world = World::new();
 
-        world.register_component::<Location>();
-        world.register_component::<Size>();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
 
         world.create_entity().with_component(Location(10.0, 11.0))?;
 
@@ -125,7 +130,7 @@ mod tests {
             .with_component::<Size>()?
             .run();
 
-        assert_eq!(results.0.len(), 1);
+        assert_eq!(results.ids.len(), 1);
 
         Ok(())
     }
@@ -134,8 +139,8 @@ mod tests {
     fn delete_entity_by_id() -> Result<()> {
         let mut world = World::new();
 
-        world.register_component::<Location>();
-        world.register_component::<Size>();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
 
         assert!(world.delete_entity_by_id(0).is_err());
 
@@ -152,9 +157,1610 @@ mod tests {
             .with_component::<Size>()?
             .run();
 
-        assert_eq!(results.0.len(), 0);
-        assert_eq!(results.1[0].len(), 0);
-        assert_eq!(results.1[1].len(), 0);
+        assert_eq!(results.ids.len(), 0);
+        assert_eq!(results.components[0].len(), 0);
+        assert_eq!(results.components[1].len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_previous_buffer() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_buffered_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(0.0, 0.0))?
+            .with_component(Size(10.0))?;
+
+        world.swap_buffers::<Location>()?;
+        world.add_component_to_entity_by_id(0, Location(1.0, 2.0))?;
+
+        let results = world
+            .query()
+            .with_buffered::<Location>(Buffer::Previous)?
+            .with_buffered::<Location>(Buffer::Current)?
+            .run();
+
+        let previous = results.components[0][0].borrow();
+        assert_eq!(
+            previous.downcast_ref::<Location>().unwrap(),
+            &Location(0.0, 0.0)
+        );
+        let current = results.components[1][0].borrow();
+        assert_eq!(
+            current.downcast_ref::<Location>().unwrap(),
+            &Location(1.0, 2.0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn archetypes() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(10.0, 11.0))?
+            .with_component(Size(10.0))?;
+        world
+            .create_entity()
+            .with_component(Location(20.0, 21.0))?
+            .with_component(Size(20.0))?;
+        world.create_entity().with_component(Size(30.0))?;
+
+        let archetypes = world.archetypes();
+
+        assert_eq!(archetypes.len(), 2);
+        assert_eq!(archetypes.get(&3), Some(&2));
+        assert_eq!(archetypes.get(&2), Some(&1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn entities_missing() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(10.0, 11.0))?
+            .with_component(Size(10.0))?;
+        world.create_entity().with_component(Location(20.0, 21.0))?;
+        world.create_entity().with_component(Size(30.0))?;
+
+        assert_eq!(world.entities_missing::<Location, Size>()?, vec![1]);
+
+        world.add_component_to_entity_by_id(1, Size(20.0))?;
+
+        assert!(world.entities_missing::<Location, Size>()?.is_empty());
+        assert!(world.entities_missing::<Location, u64>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_region() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.enable_spatial_index(10.0, |location: &Location| (location.0, location.1))?;
+
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+        world.create_entity().with_component(Location(12.0, 8.0))?;
+        world.create_entity().with_component(Location(35.0, 35.0))?;
+        world.create_entity().with_component(Size(10.0))?;
+
+        assert_eq!(world.query_region((0.0, 0.0), (15.0, 15.0)), vec![0, 1]);
+        assert_eq!(world.query_region((30.0, 30.0), (40.0, 40.0)), vec![2]);
+
+        world.delete_entity_by_id(0)?;
+
+        assert_eq!(world.query_region((0.0, 0.0), (15.0, 15.0)), vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn each_mut() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<u32>()?;
+        world.register_component::<Size>()?;
+
+        world.create_entity().with_component(1_u32)?;
+        world
+            .create_entity()
+            .with_component(2_u32)?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(Size(2.0))?;
+        world.create_entity().with_component(3_u32)?;
+
+        world.each_mut(|id, value: &mut u32| *value += id as u32 * 100)?;
+
+        let results = world.query().with_component::<u32>()?.run();
+        let values = results.components[0]
+            .iter()
+            .map(|value| *value.borrow().downcast_ref::<u32>().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.ids, vec![0, 1, 3]);
+        assert_eq!(values, vec![1, 102, 303]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_component_or() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(10.0, 11.0))?
+            .with_component(Size(10.0))?;
+        world.create_entity().with_component(Size(20.0))?;
+
+        let fallback = Location(0.0, 0.0);
+        assert_eq!(
+            world.get_component_or(0, fallback.clone())?,
+            Location(10.0, 11.0)
+        );
+        assert_eq!(world.get_component_or(1, fallback.clone())?, fallback);
+
+        world.delete_component_by_entity_id::<Location>(0)?;
+
+        assert_eq!(world.get_component_or(0, fallback.clone())?, fallback);
+        assert!(world.get_component_or(5, fallback).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_bumps_structural_tick_once() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world.create_entity().with_component(Size(1.0))?;
+        let tick = world.structural_tick();
+
+        let created = world.batch(|world| -> Result<usize> {
+            for index in 0..1000 {
+                world
+                    .create_entity()
+                    .with_component(Location(index as f32, 0.0))?;
+            }
+            world.delete_entity_by_id(0)?;
+            Ok(1000)
+        })?;
+
+        assert_eq!(created, 1000);
+        assert_eq!(world.structural_tick(), tick + 1);
+
+        world.delete_entity_by_id(1)?;
+        assert_eq!(world.structural_tick(), tick + 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_ends_when_closure_panics() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Size>()?;
+        let tick = world.structural_tick();
+
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            world.batch(|world| {
+                world.create_entity().with_component(Size(1.0)).unwrap();
+                panic!("batched system failed");
+            })
+        }));
+
+        assert!(outcome.is_err());
+        assert_eq!(world.structural_tick(), tick + 1);
+
+        world.create_entity().with_component(Size(2.0))?;
+        assert_eq!(world.structural_tick(), tick + 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn related() -> Result<()> {
+        #[derive(Debug)]
+        struct Container(usize);
+
+        let mut world = World::new();
+
+        world.register_component::<Container>()?;
+        world.register_component::<Size>()?;
+        world.track_relation(|container: &Container| container.0)?;
+
+        world.create_entity().with_component(Size(100.0))?;
+        for _ in 0..3 {
+            world
+                .create_entity()
+                .with_component(Container(0))?
+                .with_component(Size(1.0))?;
+        }
+
+        assert_eq!(world.related(0), &[1, 2, 3]);
+
+        world.delete_component_by_entity_id::<Container>(2)?;
+
+        assert_eq!(world.related(0), &[1, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_and_import_column() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<f32>()?;
+        world.register_component::<Size>()?;
+
+        world
+            .create_entity()
+            .with_component(1.0_f32)?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(2.0_f32)?;
+        world
+            .create_entity()
+            .with_component(3.0_f32)?
+            .with_component(Size(3.0))?;
+
+        let (ids, values) = world.export_column::<f32>(world.query().with_component::<Size>()?)?;
+
+        assert_eq!(ids, vec![0, 2]);
+        assert_eq!(values, vec![1.0, 3.0]);
+
+        let values = values.iter().map(|value| value * 10.0).collect::<Vec<_>>();
+        world.import_column(&ids, &values)?;
+
+        let (ids, values) = world.export_column::<f32>(world.query().with_component::<f32>()?)?;
+
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(values, vec![10.0, 2.0, 30.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn try_with_component_rejects_duplicates() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        let result = world
+            .create_entity()
+            .try_with_component(Size(10.0))?
+            .try_with_component(Size(20.0));
+
+        assert!(result.is_err());
+        assert_eq!(world.get_component_or(0, Size(0.0))?, Size(10.0));
+
+        world
+            .create_entity()
+            .try_with_component(Location(1.0, 2.0))?
+            .try_with_component(Size(30.0))?;
+
+        assert_eq!(world.get_component_or(1, Size(0.0))?, Size(30.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset() -> Result<()> {
+        let mut world = World::new();
+
+        world.add_resource(60_u32);
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world
+            .create_entity()
+            .with_component(Location(10.0, 11.0))?
+            .with_component(Size(10.0))?;
+
+        world.reset();
+
+        assert_eq!(world.get_resource::<u32>(), None);
+        assert!(world.archetypes().is_empty());
+        assert!(world.query().with_component::<Location>().is_err());
+        assert!(world.create_entity().with_component(Size(1.0)).is_err());
+
+        world.register_component::<Size>()?;
+        world.create_entity().with_component(Size(1.0))?;
+
+        let results = world.query().with_component::<Size>()?.run();
+        assert_eq!(results.ids, vec![0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn borrow_conflict_names_entity_and_type() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world.create_entity().with_component(Size(10.0))?;
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(20.0))?;
+
+        let results = world.query().with_component::<Location>()?.run();
+        let _aliased = results.components[0][0].borrow_mut();
+
+        let error = world.try_borrow_component::<Location>(1).unwrap_err();
+        match error.downcast_ref::<CustomError>() {
+            Some(CustomError::ComponentBorrowConflict { entity, type_name }) => {
+                assert_eq!(*entity, 1);
+                assert!(type_name.ends_with("Location"));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let mut size = world.try_borrow_component_mut::<Size>(1)?.unwrap();
+        size.0 += 1.0;
+        assert_eq!(*size, Size(21.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn matching_archetype_of() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.register_component::<u32>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        world
+            .create_entity()
+            .with_component(Location(2.0, 2.0))?
+            .with_component(Size(2.0))?;
+        world
+            .create_entity()
+            .with_component(Location(3.0, 3.0))?
+            .with_component(Size(3.0))?
+            .with_component(3_u32)?;
+        world.create_entity().with_component(Location(4.0, 4.0))?;
+
+        assert_eq!(world.matching_archetype_of(0)?, vec![0, 1, 2]);
+        assert!(world.matching_archetype_of(10).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn defer_add_component() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        for index in 0..4 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+
+        let results = world.query().with_component::<Location>()?.run();
+        for (&id, location) in results.ids.iter().zip(&results.components[0]) {
+            let location = location.borrow();
+            let location = location.downcast_ref::<Location>().unwrap();
+            if location.0 >= 2.0 {
+                world.defer_add_component(id, Size(location.0));
+            }
+        }
+        world.defer_add_component(3, Size(30.0));
+
+        assert_eq!(world.entities_missing::<Location, Size>()?.len(), 4);
+
+        world.flush_deferred()?;
+
+        let results = world
+            .query()
+            .with_component::<Location>()?
+            .with_component::<Size>()?
+            .run();
+        assert_eq!(results.ids, vec![2, 3]);
+        assert_eq!(world.get_component_or(3, Size(0.0))?, Size(30.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cached_query() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        for index in 0..3 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?
+                .with_component(Size(1.0))?;
+        }
+
+        let mut cached = CachedQuery::new(
+            QueryDescriptor::new()
+                .with_component::<Location>()
+                .with_component::<Size>(),
+        );
+
+        assert_eq!(cached.get(&world)?, &[0, 1, 2]);
+        let computed_at = cached.computed_at();
+
+        assert_eq!(cached.get(&world)?, &[0, 1, 2]);
+        assert_eq!(cached.computed_at(), computed_at);
+
+        world.delete_entity_by_id(1)?;
+
+        assert_eq!(cached.get(&world)?, &[0, 2]);
+        assert!(cached.computed_at() > computed_at);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_range() -> Result<()> {
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+        struct Score(u32);
+
+        let mut world = World::new();
+
+        world.register_component::<Score>()?;
+        world.index_component_sorted::<Score>()?;
+
+        for score in &[90, 110, 150, 200, 210] {
+            world.create_entity().with_component(Score(*score))?;
+        }
+
+        assert_eq!(world.query_range(Score(100), Score(200))?, vec![1, 2, 3]);
+
+        world.delete_entity_by_id(2)?;
+
+        assert_eq!(world.query_range(Score(100), Score(200))?, vec![1, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn owner_of_component() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component_with_backref::<Location>()?;
+        world.register_component::<Size>()?;
+
+        world.create_entity().with_component(Size(1.0))?;
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(Location(2.0, 2.0))?;
+
+        let results = world.query().with_component::<Location>()?.run();
+        let owners = results.components[0]
+            .iter()
+            .map(|location| world.owner_of_component::<Location>(location))
+            .collect::<Vec<_>>();
+
+        assert_eq!(owners, vec![Some(1), Some(2)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_with_raw_masks() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.register_component::<u32>()?;
+
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        world
+            .create_entity()
+            .with_component(Location(2.0, 2.0))?
+            .with_component(Size(2.0))?
+            .with_component(2_u32)?;
+        world.create_entity().with_component(Location(3.0, 3.0))?;
+
+        let required = world.mask_of::<Location>()? | world.mask_of::<Size>()?;
+        let excluded = world.mask_of::<u32>()?;
+        let raw = world
+            .query()
+            .require_mask(required)
+            .exclude_mask(excluded)
+            .run();
+
+        let typed = world
+            .query()
+            .with_component::<Location>()?
+            .with_component::<Size>()?
+            .exclude_mask(excluded)
+            .run();
+
+        assert_eq!(raw.ids, vec![0]);
+        assert_eq!(raw.ids, typed.ids);
+        assert!(raw.components.is_empty());
+        assert!(world.mask_of::<i64>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_guard() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.add_resource(3_u32);
+
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(Location(2.0, 2.0))?;
+
+        let first = world.read();
+        let second = world.read();
+        let mut query = first.query();
+        let located = query.with_component::<Location>()?.run();
+        let size = second.try_borrow_component::<Size>(0)?;
+
+        assert_eq!(located.ids, vec![0, 1]);
+        assert_eq!(size.as_deref(), Some(&Size(1.0)));
+        assert_eq!(second.get_resource::<u32>(), Some(&3));
+        assert_eq!(first.entities_missing::<Location, Size>()?, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn string_tags() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+
+        for index in 0..3 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+
+        world.add_tag(0, "enemy")?;
+        world.add_tag(1, "enemy")?;
+        world.add_tag(1, "flying")?;
+        world.add_tag(2, "flying")?;
+
+        let tagged = |world: &World, tag: &str| world.query().with_string_tag(tag).run().ids;
+
+        assert_eq!(tagged(&world, "enemy"), vec![0, 1]);
+        assert_eq!(tagged(&world, "flying"), vec![1, 2]);
+
+        world.remove_tag(1, "enemy")?;
+        assert_eq!(tagged(&world, "enemy"), vec![0]);
+
+        world.delete_entity_by_id(2)?;
+        assert_eq!(tagged(&world, "flying"), vec![1]);
+
+        world.create_entity().with_component(Location(9.0, 9.0))?;
+        assert_eq!(tagged(&world, "flying"), vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_component_storages() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component_with_storage::<Location>(DenseStorage::default())?;
+        world.register_component_with_storage::<Size>(SparseStorage::default())?;
+
+        for index in 0..4 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+        world.add_component_to_entity_by_id(3, Size(3.0))?;
+        world.add_component_to_entity_by_id(1, Size(1.0))?;
+        world.add_component_to_entity_by_id(2, Size(2.0))?;
+        world.delete_component_by_entity_id::<Size>(2)?;
+
+        let results = world
+            .query()
+            .with_component::<Location>()?
+            .with_component::<Size>()?
+            .run();
+
+        assert_eq!(results.ids, vec![1, 3]);
+        let sizes = results.components[1]
+            .iter()
+            .map(|size| size.borrow().downcast_ref::<Size>().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![Size(1.0), Size(3.0)]);
+
+        world.delete_entity_by_id(3)?;
+        world.create_entity().with_component(Location(9.0, 9.0))?;
+        assert_eq!(world.get_component_or(3, Size(0.0))?, Size(0.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn checked_ids_expire_on_delete() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+
+        let checked = world.checked_id(1);
+        world.add_component_to_entity_by_id(0, Location(2.0, 2.0))?;
+        world.create_entity().with_component(Location(3.0, 3.0))?;
+
+        let id = world.resolve(checked)?;
+        assert_eq!(
+            world.get_component_or(id, Location(0.0, 0.0))?,
+            Location(1.0, 1.0)
+        );
+
+        world.delete_entity_by_id(2)?;
+
+        let error = world.resolve(checked).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::StaleEntityId)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn undo_and_redo_edits() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_cloneable_component::<Location>()?;
+        world.register_cloneable_component::<Size>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+
+        let locations = |world: &World| -> Result<Vec<Location>> {
+            let results = world.query().with_component::<Location>()?.run();
+            Ok(results.components[0]
+                .iter()
+                .map(|location| {
+                    location
+                        .borrow()
+                        .downcast_ref::<Location>()
+                        .unwrap()
+                        .clone()
+                })
+                .collect())
+        };
+
+        world.begin_edit()?;
+        world.add_component_to_entity_by_id(0, Location(1.0, 1.0))?;
+        world.commit_edit();
+
+        world.begin_edit()?;
+        world
+            .create_entity()
+            .with_component(Location(2.0, 2.0))?
+            .with_component(Size(2.0))?;
+        world.commit_edit();
+
+        assert_eq!(
+            locations(&world)?,
+            vec![Location(1.0, 1.0), Location(2.0, 2.0)]
+        );
+
+        assert!(world.undo()?);
+        assert_eq!(locations(&world)?, vec![Location(1.0, 1.0)]);
+        assert!(world.query().with_component::<Size>()?.run().ids.is_empty());
+
+        assert!(world.undo()?);
+        assert_eq!(locations(&world)?, vec![Location(0.0, 0.0)]);
+
+        assert!(world.redo()?);
+        assert_eq!(locations(&world)?, vec![Location(1.0, 1.0)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn component_schema() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.register_reflection::<Location>(|| {
+            vec![FieldSchema::new("0", "f32"), FieldSchema::new("1", "f32")]
+        })?;
+        world.register_reflection::<Size>(|| vec![FieldSchema::new("0", "f32")])?;
+
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+
+        assert_eq!(
+            world.schema(),
+            vec![
+                ComponentSchema {
+                    type_name: std::any::type_name::<Location>(),
+                    bit_index: 0,
+                    entity_count: 2,
+                    fields: vec![FieldSchema::new("0", "f32"), FieldSchema::new("1", "f32")],
+                },
+                ComponentSchema {
+                    type_name: std::any::type_name::<Size>(),
+                    bit_index: 1,
+                    entity_count: 1,
+                    fields: vec![FieldSchema::new("0", "f32")],
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_with_resource() -> Result<()> {
+        struct AreaOfEffect {
+            center: Location,
+            radius: f32,
+        }
+
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        for index in 0..5 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?
+                .with_component(Size(1.0))?;
+        }
+
+        let query = |world: &World| {
+            world.query_with_resource(|query, aoe: &AreaOfEffect| {
+                query
+                    .with_component::<Size>()?
+                    .filter(move |location: &Location| {
+                        let (dx, dy) = (location.0 - aoe.center.0, location.1 - aoe.center.1);
+                        (dx * dx + dy * dy).sqrt() <= aoe.radius
+                    })?;
+                Ok(())
+            })
+        };
+
+        let error = query(&world).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::ResourceNotFound)
+        );
+
+        world.add_resource(AreaOfEffect {
+            center: Location(2.0, 0.0),
+            radius: 1.5,
+        });
+        let results = query(&world)?;
+
+        assert_eq!(results.ids, vec![1, 2, 3]);
+        assert_eq!(results.components.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn despawn_reasons() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        for index in 0..3 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+
+        world.set_despawn_log_cap(8);
+        world.despawn_with_reason(2, "left the map")?;
+        world.delete_entity_by_id(1)?;
+        world.despawn_with_reason(0, "destroyed")?;
+
+        assert_eq!(
+            world.despawn_log(),
+            &[(2, "left the map".to_owned()), (0, "destroyed".to_owned())]
+        );
+        assert!(world
+            .query()
+            .with_component::<Location>()?
+            .run()
+            .ids
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn changed_or_added() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        let updated = |world: &World| -> Result<Vec<usize>> {
+            Ok(world.query().changed_or_added::<Location>()?.run().ids)
+        };
+
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+        world.create_entity().with_component(Size(2.0))?;
+        assert_eq!(updated(&world)?, vec![0, 1]);
+
+        world.clear_trackers();
+        assert!(updated(&world)?.is_empty());
+
+        world.add_component_to_entity_by_id(2, Location(2.0, 2.0))?;
+        world.each_mut(|id, location: &mut Location| {
+            if id == 0 {
+                location.0 += 1.0;
+            }
+        })?;
+        world.add_component_to_entity_by_id(1, Size(1.0))?;
+        assert_eq!(updated(&world)?, vec![0, 1, 2]);
+
+        world.clear_trackers();
+        world.add_component_to_entity_by_id(1, Location(5.0, 5.0))?;
+        assert_eq!(updated(&world)?, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spawn_prototype() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.register_prototype("goblin", |goblin| {
+            goblin
+                .with_component(Location(0.0, 0.0))
+                .with_component(Size(0.5));
+        });
+
+        world.create_entity().with_component(Location(9.0, 9.0))?;
+        let first = world.spawn_prototype("goblin")?;
+        let second = world.spawn_prototype("goblin")?;
+
+        assert_eq!((first, second), (1, 2));
+        let results = world
+            .query()
+            .with_component::<Location>()?
+            .with_component::<Size>()?
+            .run();
+        assert_eq!(results.ids, vec![1, 2]);
+        assert!(!Rc::ptr_eq(
+            &results.components[1][0],
+            &results.components[1][1]
+        ));
+
+        let error = world.spawn_prototype("dragon").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::UnknownPrototype("dragon".to_owned()))
+        );
+
+        Ok(())
+    }
+
+    fn signature_width<M: Mask>() -> Result<()> {
+        let mut world = WorldGeneric::<M>::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world
+            .create_entity()
+            .with_component(Location(0.0, 0.0))?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+
+        let both = world.mask_of::<Location>()? | world.mask_of::<Size>()?;
+        assert_eq!(both.count_ones(), 2);
+        assert_eq!(world.archetypes().get(&both), Some(&1));
+        assert_eq!(world.query().require_mask(both).run().ids, vec![0]);
+
+        let located = world.query().with_component::<Location>()?.run();
+        assert_eq!(located.ids, vec![0, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn signature_widths() -> Result<()> {
+        signature_width::<u32>()?;
+        signature_width::<u128>()
+    }
+
+    struct Marker<const N: usize>;
+
+    macro_rules! register_markers {
+        ($world:expr; $($n:literal)*) => {
+            $($world.register_component::<Marker<$n>>()?;)*
+        };
+    }
+
+    #[test]
+    fn more_than_thirty_two_components() -> Result<()> {
+        let mut world = World::new();
+
+        register_markers!(world; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39);
+        world.create_entity().with_component(Marker::<0>)?;
+        world
+            .create_entity()
+            .with_component(Marker::<31>)?
+            .with_component(Marker::<39>)?;
+
+        assert_eq!(world.component_count::<Marker<39>>()?, 1);
+        assert_eq!(world.mask_of::<Marker<39>>()?, 1 << 39);
+        let last = world.query().with_component::<Marker<39>>()?.run();
+        assert_eq!(last.ids, vec![1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn too_many_components() -> Result<()> {
+        let mut world = WorldGeneric::<u32>::new();
+
+        register_markers!(world; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31);
+        let error = world.register_component::<Marker<32>>().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::TooManyComponents(32))
+        );
+        world.register_component::<Marker<31>>()?;
+        assert!(world.mask_of::<Marker<32>>().is_err());
+
+        Ok(())
+    }
+
+    fn overlapping_worlds() -> Result<(World, World)> {
+        let mut scene = World::new();
+        scene.register_component::<Location>()?;
+        scene.create_entity().with_component(Location(10.0, 10.0))?;
+        scene.create_entity().with_component(Location(11.0, 11.0))?;
+        scene.add_tag(1, "scene")?;
+
+        let mut world = World::new();
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world
+            .create_entity()
+            .with_component(Location(0.0, 0.0))?
+            .with_component(Size(0.0))?;
+
+        Ok((world, scene))
+    }
+
+    fn locations(world: &World) -> Result<Vec<(usize, Location)>> {
+        let results = world.query().with_component::<Location>()?.run();
+        Ok(results
+            .ids
+            .into_iter()
+            .zip(&results.components[0])
+            .map(|(id, location)| {
+                (
+                    id,
+                    location
+                        .borrow()
+                        .downcast_ref::<Location>()
+                        .unwrap()
+                        .clone(),
+                )
+            })
+            .collect())
+    }
+
+    #[test]
+    fn merge_with_remap() -> Result<()> {
+        let (mut world, scene) = overlapping_worlds()?;
+
+        let placed = world.merge_with(scene, MergeStrategy::Remap)?;
+
+        assert_eq!(placed, HashMap::from([(0, 1), (1, 2)]));
+        assert_eq!(
+            locations(&world)?,
+            vec![
+                (0, Location(0.0, 0.0)),
+                (1, Location(10.0, 10.0)),
+                (2, Location(11.0, 11.0)),
+            ]
+        );
+        assert_eq!(world.query().with_string_tag("scene").run().ids, vec![2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_with_overwrite() -> Result<()> {
+        let (mut world, scene) = overlapping_worlds()?;
+
+        let placed = world.merge_with(scene, MergeStrategy::Overwrite)?;
+
+        assert_eq!(placed, HashMap::from([(0, 0), (1, 1)]));
+        assert_eq!(
+            locations(&world)?,
+            vec![(0, Location(10.0, 10.0)), (1, Location(11.0, 11.0))]
+        );
+        assert!(world.query().with_component::<Size>()?.run().ids.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_with_skip() -> Result<()> {
+        let (mut world, scene) = overlapping_worlds()?;
+
+        let placed = world.merge_with(scene, MergeStrategy::Skip)?;
+
+        assert_eq!(placed, HashMap::from([(1, 1)]));
+        assert_eq!(
+            locations(&world)?,
+            vec![(0, Location(0.0, 0.0)), (1, Location(11.0, 11.0))]
+        );
+        assert_eq!(world.query().with_component::<Size>()?.run().ids, vec![0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn on_add_batched() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+
+        let batches = Rc::new(RefCell::new(vec![]));
+        let seen = batches.clone();
+        world.on_add_batched::<Size>(move |ids| seen.borrow_mut().push(ids.to_vec()))?;
+
+        for index in 0..3 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+        world.add_component_to_entity_by_id(2, Size(2.0))?;
+        world.add_component_to_entity_by_id(0, Size(0.0))?;
+        world.add_component_to_entity_by_id(1, Size(1.0))?;
+        world.add_component_to_entity_by_id(0, Size(5.0))?;
+        assert!(batches.borrow().is_empty());
+
+        world.end_frame()?;
+        assert_eq!(*batches.borrow(), vec![vec![0, 1, 2]]);
+
+        world.add_component_to_entity_by_id(1, Location(9.0, 9.0))?;
+        world.end_frame()?;
+        assert_eq!(batches.borrow().len(), 1);
+
+        world.create_entity().with_component(Size(3.0))?;
+        world.end_frame()?;
+        assert_eq!(*batches.borrow(), vec![vec![0, 1, 2], vec![3]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn userdata() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+
+        let handle = Box::into_raw(Box::new(5_i32));
+        world.set_userdata(1, handle as u64)?;
+        assert_eq!(world.userdata(0), None);
+
+        let restored = world.userdata(1).unwrap() as *mut i32;
+        assert_eq!(unsafe { *restored }, 5);
+
+        world.delete_entity_by_id(1)?;
+        assert_eq!(world.userdata(1), None);
+        drop(unsafe { Box::from_raw(restored) });
+
+        Ok(())
+    }
+
+    #[test]
+    fn commands_apply_at_end_frame() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+
+        let system = |world: ReadGuard| {
+            world.commands().despawn(0);
+            world.commands().spawn(|entity| {
+                entity
+                    .with_component(Location(2.0, 2.0))
+                    .with_component(Size(2.0));
+            });
+        };
+        system(world.read());
+
+        assert_eq!(world.query().with_component::<Size>()?.run().ids, vec![]);
+
+        world.end_frame()?;
+        assert_eq!(world.query().with_component::<Size>()?.run().ids, vec![0]);
+        assert_eq!(
+            world.query().with_component::<Location>()?.run().ids,
+            vec![0, 1]
+        );
+
+        world.end_frame()?;
+        assert_eq!(world.query().with_component::<Size>()?.run().ids, vec![0]);
+
+        world.commands().despawn(9);
+        world.commands().despawn(1);
+        world.commands().insert_resource(60_u32);
+        assert!(world.end_frame().is_err());
+        assert_eq!(
+            world.query().with_component::<Location>()?.run().ids,
+            vec![0]
+        );
+        assert_eq!(world.get_resource::<u32>(), Some(&60));
+        world.end_frame()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_parent_component() -> Result<()> {
+        struct Parent(usize);
+
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Parent>()?;
+        world.track_relation(|parent: &Parent| parent.0)?;
+
+        world.create_entity().with_component(Location(5.0, 5.0))?;
+        world
+            .create_entity()
+            .with_component(Location(1.0, 0.0))?
+            .with_component(Parent(0))?;
+        world.create_entity().with_component(Location(2.0, 0.0))?;
+        assert_eq!(world.owner(1), Some(0));
+
+        let QueryResult { ids, parents, .. } = world
+            .query()
+            .with_component::<Location>()?
+            .with_parent_component::<Location>()?
+            .run();
+
+        assert_eq!(ids, vec![0, 1, 2]);
+        let parent_locations = parents[0]
+            .iter()
+            .map(|parent| {
+                parent.as_ref().map(|location| {
+                    location
+                        .borrow()
+                        .downcast_ref::<Location>()
+                        .unwrap()
+                        .clone()
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parent_locations, vec![None, Some(Location(5.0, 5.0)), None]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn structural_events() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+
+        world.record_structural_events();
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        world.add_component_to_entity_by_id(0, Size(0.0))?;
+        world.add_component_to_entity_by_id(0, Size(2.0))?;
+        world.delete_component_by_entity_id::<Location>(0)?;
+        world.delete_entity_by_id(1)?;
+
+        let location = TypeId::of::<Location>();
+        let size = TypeId::of::<Size>();
+        assert_eq!(
+            world.drain_structural_events(),
+            vec![
+                StructuralEvent::EntitySpawned(1),
+                StructuralEvent::ComponentAdded(1, location),
+                StructuralEvent::ComponentAdded(1, size),
+                StructuralEvent::ComponentAdded(0, size),
+                StructuralEvent::ComponentRemoved(0, location),
+                StructuralEvent::ComponentRemoved(1, location),
+                StructuralEvent::ComponentRemoved(1, size),
+                StructuralEvent::EntityDespawned(1),
+            ]
+        );
+        assert!(world.drain_structural_events().is_empty());
+
+        world.delete_component_by_entity_id::<Size>(0)?;
+        assert_eq!(
+            world.drain_structural_events(),
+            vec![
+                StructuralEvent::ComponentRemoved(0, size),
+                StructuralEvent::EntityDespawned(0),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_component_reads_previous_values() -> Result<()> {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Heat {
+            cell: usize,
+            value: f32,
+        }
+
+        let mut world = World::new();
+        world.register_component::<Heat>()?;
+        for (cell, value) in [0.0, 9.0, 0.0, 3.0].iter().copied().enumerate() {
+            world.create_entity().with_component(Heat { cell, value })?;
+        }
+
+        let cells = world
+            .query()
+            .with_component::<Heat>()?
+            .run()
+            .components
+            .remove(0);
+        let value_at = |cell: usize| {
+            cells.get(cell).map_or(0.0, |heat| {
+                heat.borrow().downcast_ref::<Heat>().unwrap().value
+            })
+        };
+        world.map_component(|heat: &Heat| Heat {
+            cell: heat.cell,
+            value: (value_at(heat.cell.wrapping_sub(1)) + heat.value + value_at(heat.cell + 1))
+                / 3.0,
+        })?;
+
+        let values = (0..4).map(value_at).collect::<Vec<_>>();
+        assert_eq!(values, vec![3.0, 3.0, 4.0, 1.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn despawn_through_query_control() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        for index in 0..5 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?
+                .with_component(Size(index as f32))?;
+        }
+
+        let control = world
+            .query()
+            .with_component::<Size>()?
+            .for_each_ctl(|_, row, control| {
+                if row[0].borrow().downcast_ref::<Size>().unwrap().0 < 2.0 {
+                    control.despawn_current();
+                }
+            });
+        assert_eq!(control.despawned(), &[0, 1]);
+        assert_eq!(world.entity_count(), 5);
+
+        world.end_frame()?;
+
+        assert_eq!(
+            world.query().with_component::<Size>()?.run().ids,
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            world.query().with_component::<Location>()?.run().ids,
+            vec![2, 3, 4]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cached_component_reads_once_per_frame() -> Result<()> {
+        let mut world = World::new();
+        world.register_component::<Location>()?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+
+        // A held mutable borrow makes every read of the underlying component fail.
+        let held = world.try_borrow_component_mut::<Location>(0)?;
+        assert!(world.cached_component::<Location>(0).is_err());
+        drop(held);
+
+        assert_eq!(
+            world.cached_component::<Location>(0)?,
+            Some(Location(1.0, 1.0))
+        );
+        let held = world.try_borrow_component_mut::<Location>(0)?;
+        assert_eq!(
+            world.cached_component::<Location>(0)?,
+            Some(Location(1.0, 1.0))
+        );
+        drop(held);
+        let error = world.cached_component::<Size>(0).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::ComponentNotRegistered)
+        );
+
+        world.end_frame()?;
+        let held = world.try_borrow_component_mut::<Location>(0)?;
+        assert!(world.cached_component::<Location>(0).is_err());
+        drop(held);
+
+        world.register_component::<Size>()?;
+        world.add_component_to_entity_by_id(0, Size(1.0))?;
+        assert_eq!(
+            world.cached_component::<Location>(0)?,
+            Some(Location(1.0, 1.0))
+        );
+        world.delete_component_by_entity_id::<Location>(0)?;
+        assert_eq!(world.cached_component::<Location>(0)?, None);
+        world.add_component_to_entity_by_id(0, Location(2.0, 2.0))?;
+        assert_eq!(
+            world.cached_component::<Location>(0)?,
+            Some(Location(2.0, 2.0))
+        );
+
+        world.delete_entity_by_id(0)?;
+        assert_eq!(world.cached_component::<Location>(0)?, None);
+        world.create_entity().with_component(Location(3.0, 3.0))?;
+        assert_eq!(
+            world.cached_component::<Location>(0)?,
+            Some(Location(3.0, 3.0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_region() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+        world.create_entity().with_component(Location(8.0, 8.0))?;
+        world.track_dirty_region(|location: &Location| (location.0, location.1))?;
+        assert_eq!(world.dirty_region(), None);
+
+        world.add_component_to_entity_by_id(0, Location(3.0, -2.0))?;
+        world.add_component_to_entity_by_id(1, Size(1.0))?;
+        assert_eq!(world.dirty_region(), Some((1.0, -2.0, 3.0, 1.0)));
+
+        world.each_mut(|id, location: &mut Location| {
+            if id == 1 {
+                location.0 = 10.0;
+            }
+        })?;
+        let (min_x, min_y, max_x, max_y) = world.dirty_region().unwrap();
+        for location in [Location(1.0, 1.0), Location(3.0, -2.0), Location(10.0, 8.0)].iter() {
+            assert!(min_x <= location.0 && location.0 <= max_x);
+            assert!(min_y <= location.1 && location.1 <= max_y);
+        }
+
+        world.end_frame()?;
+        assert_eq!(world.dirty_region(), None);
+
+        world.delete_entity_by_id(0)?;
+        world.create_entity().with_component(Location(-1.0, 5.0))?;
+        assert_eq!(world.dirty_region(), Some((-1.0, -2.0, 3.0, 5.0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn collect_owned_pairs() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.register_component::<u32>()?;
+        world
+            .create_entity()
+            .with_component(Location(0.0, 0.0))?
+            .with_component(Size(1.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+        world
+            .create_entity()
+            .with_component(Location(2.0, 2.0))?
+            .with_component(Size(3.0))?;
+
+        let pairs = {
+            let mut query = world.query();
+            query
+                .with_component::<Location>()?
+                .with_component::<Size>()?;
+            assert!(query.collect_pairs::<Location, u32>().is_err());
+            query.collect_pairs::<Location, Size>()?
+        };
+        world.delete_entity_by_id(0)?;
+
+        assert_eq!(
+            pairs,
+            vec![
+                (Location(0.0, 0.0), Size(1.0)),
+                (Location(2.0, 2.0), Size(3.0)),
+            ]
+        );
+        let error = world
+            .query()
+            .with_component::<Location>()?
+            .collect_pairs::<Location, Size>()
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CustomError>(),
+            Some(CustomError::ComponentNotInQuery(_))
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn detect_query_leaks() -> Result<()> {
+        use ecs_lib_rs::LeakPolicy;
+
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.create_entity().with_component(Location(0.0, 0.0))?;
+        world.create_entity().with_component(Location(1.0, 1.0))?;
+        world.detect_query_leaks(Some(LeakPolicy::Warn));
+
+        let results = world.query().with_component::<Location>()?.run();
+        drop(results);
+        world.query().with_component::<Location>()?.run();
+        assert_eq!(world.query_leaks_detected(), 0);
+
+        let held = world.query().with_component::<Location>()?.run();
+        world.query().with_component::<Location>()?.run();
+        assert_eq!(world.query_leaks_detected(), 1);
+        drop(held);
+
+        world.detect_query_leaks(Some(LeakPolicy::Panic));
+        let held = world.query().with_component::<Location>()?.run();
+        let rerun = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            world.query().with_component::<Location>().unwrap().run();
+        }));
+        let message = rerun.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(*message, CustomError::QueryResultsHeld(2).to_string());
+        drop(held);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_entities() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        world.add_resource(60_u32);
+        world
+            .create_entity()
+            .with_component(Location(1.0, 1.0))?
+            .with_component(Size(1.0))?;
+        let kept = world
+            .create_entity()
+            .with_component(Location(2.0, 2.0))?
+            .entity();
+        world.add_tag(1, "player")?;
+
+        world.clear_entities();
+
+        assert!(world.is_empty());
+        assert!(world
+            .query()
+            .with_component::<Location>()?
+            .run()
+            .ids
+            .is_empty());
+        assert!(world.query().with_string_tag("player").run().ids.is_empty());
+        assert!(world.delete_entity_by_id(kept).is_err());
+        assert_eq!(world.get_resource::<u32>(), Some(&60));
+
+        world.create_entity().with_component(Size(3.0))?;
+        let results = world.query().with_component::<Size>()?.run();
+        assert_eq!(results.ids, vec![0]);
+        assert_eq!(world.get_component_or(0, Size(0.0))?, Size(3.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn commands_apply_after_iterating() -> Result<()> {
+        let mut world = World::new();
+
+        world.register_component::<Location>()?;
+        world.register_component::<Size>()?;
+        for index in 0..6 {
+            world
+                .create_entity()
+                .with_component(Location(index as f32, 0.0))?;
+        }
+
+        let results = world.query().with_component::<Location>()?.run();
+        let commands = world.commands();
+        for (&id, location) in results.ids.iter().zip(&results.components[0]) {
+            let location = location.borrow();
+            let location = location.downcast_ref::<Location>().unwrap();
+            if id % 2 == 0 {
+                commands.despawn(id);
+            } else {
+                commands.add_component(id, Size(location.0));
+            }
+        }
+        commands.remove_component::<Location>(5);
+        world.apply_commands()?;
+
+        assert_eq!(world.entity_count(), 3);
+        assert_eq!(
+            world.query().with_component::<Location>()?.run().ids,
+            vec![1, 3]
+        );
+        assert_eq!(
+            world.query().with_component::<Size>()?.run().ids,
+            vec![1, 3, 5]
+        );
+        assert_eq!(world.get_component_or(3, Size(0.0))?, Size(3.0));
 
         Ok(())
     }
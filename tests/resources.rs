@@ -3,7 +3,8 @@ use std::ops::{Deref, DerefMut};
 #[cfg(test)]
 mod tests {
     use crate::FpsResource;
-    use ecs_lib_rs::World;
+    use ecs_lib_rs::{CustomError, Events, QueryResult, StepRemainder, World};
+    use eyre::Result;
     use std::any::Any;
 
     #[test]
@@ -17,6 +18,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::explicit_auto_deref)]
     fn get_resources_mutably() {
         let mut world = World::new();
         let fps = world.get_resource_mut::<FpsResource>();
@@ -33,6 +35,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::type_id_on_box)]
     fn delete_resource() {
         let mut world = World::new();
         assert_eq!(
@@ -45,6 +48,292 @@ mod tests {
             Some((Box::new(FpsResource(60)) as Box<dyn Any>).type_id())
         );
     }
+
+    #[test]
+    fn scoped_resource() {
+        let mut world = World::new();
+        let mut frame_input = vec![1_u8, 2, 3];
+
+        let (sum, taken) = world.with_scoped_resource(&mut frame_input, |world| {
+            let input = world.scoped();
+            input.push(4);
+            let sum = input.iter().sum::<u8>();
+            (sum, std::mem::take(&mut **world))
+        });
+
+        assert_eq!(sum, 10);
+        frame_input.push(5);
+        assert_eq!(frame_input, vec![1, 2, 3, 4, 5]);
+        assert!(taken.get_resource::<Vec<u8>>().is_none());
+        assert!(world.get_resource::<Vec<u8>>().is_none());
+    }
+
+    #[test]
+    fn nested_scoped_resources() {
+        let mut world = World::new();
+        let mut outer = FpsResource(30);
+        let mut inner = FpsResource(60);
+
+        world.with_scoped_resource(&mut outer, |world| {
+            world.with_scoped_resource(&mut inner, |world| {
+                assert_eq!(world.scoped(), &mut FpsResource(60));
+            });
+            assert_eq!(world.scoped(), &mut FpsResource(30));
+        });
+
+        assert_eq!(world.get_resource::<FpsResource>(), None);
+    }
+
+    #[test]
+    fn fixed_step() {
+        let mut world = World::new();
+        world.add_resource(FpsResource(0));
+
+        let mut counts = vec![];
+        for dt in [0.0625, 0.125, 0.3125, 0.5625] {
+            world
+                .step(dt, 0.125, |world| {
+                    **world.get_resource_mut::<FpsResource>().unwrap() += 1;
+                })
+                .unwrap();
+            counts.push(**world.get_resource::<FpsResource>().unwrap());
+        }
+
+        assert_eq!(counts, vec![0, 1, 4, 8]);
+        assert_eq!(
+            world.get_resource::<StepRemainder>(),
+            Some(&StepRemainder {
+                remainder: 0.0625,
+                alpha: 0.5,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_step() {
+        let mut world = World::new();
+        let mut steps = 0;
+
+        for (dt, fixed) in [
+            (f32::INFINITY, 0.125),
+            (f32::NAN, 0.125),
+            (-0.125, 0.125),
+            (0.125, 0.0),
+            (0.125, -0.125),
+            (0.125, f32::NAN),
+        ] {
+            let error = world.step(dt, fixed, |_| steps += 1).unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<CustomError>(),
+                Some(&CustomError::InvalidTimestep)
+            );
+        }
+
+        assert_eq!(steps, 0);
+        assert_eq!(world.get_resource::<StepRemainder>(), None);
+        world.step(0.25, 0.125, |_| steps += 1).unwrap();
+        assert_eq!(steps, 2);
+    }
+
+    #[test]
+    fn for_each_with_res() -> Result<()> {
+        struct Scale(f32);
+
+        let mut world = World::new();
+        world.register_component::<f32>()?;
+        world.register_component::<u32>()?;
+        world.create_entity().with_component(1.0_f32)?;
+        world.create_entity().with_component(2_u32)?;
+        world.create_entity().with_component(3.0_f32)?;
+
+        assert!(world
+            .for_each_with_res(|_, _: &mut f32, _: &Scale| {})
+            .is_err());
+
+        world.add_resource(Scale(2.5));
+        let mut visited = vec![];
+        world.for_each_with_res(|id, value: &mut f32, scale: &Scale| {
+            visited.push(id);
+            *value *= scale.0;
+        })?;
+
+        assert_eq!(visited, vec![0, 2]);
+        assert_eq!(world.get_component_or(0, 0.0_f32)?, 2.5);
+        assert_eq!(world.get_component_or(2, 0.0_f32)?, 7.5);
+        assert_eq!(world.get_component_or(1, 0_u32)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn commands_change_resources() -> Result<()> {
+        let mut world = World::new();
+        world.register_component::<u32>()?;
+        world.add_resource(1_u64);
+
+        let system = |world: &World| {
+            world.commands().insert_resource(FpsResource(30));
+            world.commands().remove_resource::<u64>();
+            world.commands().spawn(|entity| {
+                entity.with_component(3_u32);
+            });
+        };
+        system(&world);
+
+        assert_eq!(world.get_resource::<FpsResource>(), None);
+        assert_eq!(world.get_resource::<u64>(), Some(&1));
+
+        world.end_frame()?;
+        assert_eq!(world.get_resource::<FpsResource>(), Some(&FpsResource(30)));
+        assert_eq!(world.get_resource::<u64>(), None);
+        assert_eq!(world.query().with_component::<u32>()?.run().ids, vec![0]);
+
+        world.commands().insert_resource(FpsResource(60));
+        world.commands().despawn(5);
+        assert!(world.end_frame().is_err());
+        assert_eq!(world.get_resource::<FpsResource>(), Some(&FpsResource(60)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_and_restore_resource() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Config {
+            gravity: f32,
+            names: Vec<String>,
+        }
+
+        let mut world = World::new();
+        assert_eq!(world.snapshot_resource::<Config>(), None);
+
+        world.add_resource(Config {
+            gravity: 9.8,
+            names: vec!["earth".to_owned()],
+        });
+        let saved = world.snapshot_resource::<Config>().unwrap();
+
+        let config = world.get_resource_mut::<Config>().unwrap();
+        config.gravity = 1.6;
+        config.names.push("moon".to_owned());
+        assert_eq!(world.get_resource::<Config>().unwrap().names.len(), 2);
+
+        world.restore_resource(saved.clone());
+        assert_eq!(world.get_resource::<Config>(), Some(&saved));
+    }
+
+    #[test]
+    fn insert_resource_tuple() {
+        #[derive(Debug, PartialEq)]
+        struct GravityResource(f32);
+
+        let mut world = World::new();
+        world.add_resource(FpsResource(30));
+        world.insert_resources((FpsResource(60), GravityResource(9.8), 3_u8));
+
+        assert_eq!(world.get_resource::<FpsResource>(), Some(&FpsResource(60)));
+        assert_eq!(
+            world.get_resource::<GravityResource>(),
+            Some(&GravityResource(9.8))
+        );
+        assert_eq!(world.get_resource::<u8>(), Some(&3));
+
+        world.insert_resources((1_u8, 2_u8));
+        assert_eq!(world.get_resource::<u8>(), Some(&2));
+    }
+
+    #[test]
+    fn query_component_or_resource() -> Result<()> {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Gravity(f32);
+
+        let mut world = World::new();
+        world.register_component::<u32>()?;
+        world.register_component::<Gravity>()?;
+        world.add_resource(Gravity(9.8));
+
+        world.create_entity().with_component(0_u32)?;
+        world
+            .create_entity()
+            .with_component(1_u32)?
+            .with_component(Gravity(1.6))?;
+        world.create_entity().with_component(2_u32)?;
+
+        let QueryResult {
+            ids,
+            components: columns,
+            ..
+        } = world
+            .query()
+            .with_component::<u32>()?
+            .with_or_resource::<Gravity>()?
+            .run();
+        let gravities = columns[1]
+            .iter()
+            .map(|gravity| gravity.borrow().downcast_ref::<Gravity>().unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(gravities, vec![9.8, 1.6, 9.8]);
+
+        world.add_resource(2.0_f64);
+        let QueryResult {
+            ids,
+            components: columns,
+            ..
+        } = world
+            .query()
+            .with_component::<u32>()?
+            .with_or_resource::<f64>()?
+            .run();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(*columns[1][2].borrow().downcast_ref::<f64>().unwrap(), 2.0);
+
+        world.remove_resource::<Gravity>();
+        let QueryResult { ids, .. } = world
+            .query()
+            .with_component::<u32>()?
+            .with_or_resource::<Gravity>()?
+            .run();
+        assert_eq!(ids, vec![1]);
+        let error = world.query().with_or_resource::<i8>().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CustomError>(),
+            Some(&CustomError::ComponentNotRegistered)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn replace_resource() {
+        let mut world = World::new();
+        assert_eq!(world.replace_resource(FpsResource(30)), None);
+        assert_eq!(
+            world.replace_resource(FpsResource(60)),
+            Some(FpsResource(30))
+        );
+        assert_eq!(world.get_resource::<FpsResource>(), Some(&FpsResource(60)));
+    }
+
+    #[test]
+    fn read_events() {
+        let mut world = World::new();
+        assert_eq!(world.read_events::<u32>().count(), 0);
+
+        world.send_event(1_u32);
+        assert_eq!(world.read_events::<u32>().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(world.read_events::<u32>().collect::<Vec<_>>(), vec![&1]);
+
+        world.get_resource_mut::<Events<u32>>().unwrap().send(2);
+        world.end_frame().unwrap();
+        assert_eq!(world.read_events::<u32>().collect::<Vec<_>>(), vec![&1, &2]);
+
+        world.send_event(3_u32);
+        world.end_frame().unwrap();
+        assert_eq!(world.read_events::<u32>().collect::<Vec<_>>(), vec![&3]);
+        world.end_frame().unwrap();
+        assert_eq!(world.read_events::<u32>().count(), 0);
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]